@@ -0,0 +1,414 @@
+use std::{collections::BTreeSet, ffi::OsString, path::{Path, PathBuf}};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::error::Error;
+
+// Centiseconds, an LCM of loop lengths beyond this falls back to the longest loop
+const MAX_DURATION: u64 = 1500;
+const MAX_FRAMES: usize = 500;
+// Browsers play anything faster than 20ms at 100ms, so do the same when syncing
+const MIN_DELAY: u32 = 2;
+const DEFAULT_DELAY: u32 = 10;
+
+// Gaps between strip emotes over this are almost certainly a typo
+pub const MAX_SPACING: u32 = 64;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StripConfig {
+    // Pixels between two emotes, requests can pick their own with ?spacing=
+    pub spacing: u32,
+    // "transparent" or a hex color like ff8800, requests can pick their own with ?background=
+    pub background: String,
+}
+
+impl Default for StripConfig {
+    fn default() -> Self {
+        Self {
+            spacing: 4,
+            background: "transparent".to_owned(),
+        }
+    }
+}
+
+// Hex colors go without the '#' so they fit in a URL as they are
+pub fn parse_background(input: &str) -> Result<String, String> {
+    let input = input.trim_start_matches('#').to_ascii_lowercase();
+
+    if input == "transparent" || input == "none" {
+        return Ok("none".to_owned());
+    }
+
+    if [6, 8].contains(&input.len()) && input.chars().all(|x| x.is_ascii_hexdigit()) {
+        return Ok(format!("#{input}"));
+    }
+
+    Err(format!("unknown background {input}, expected transparent or a hex color like ff8800"))
+}
+
+// How pulled emotes are put together into one image
+#[derive(Debug)]
+pub enum Composition {
+    // The first emote with every other one drawn centered on top of it
+    Stack,
+    // Side by side, scaled to the same height. The background is "none" or "#rrggbb(aa)"
+    Strip {
+        spacing: u32,
+        background: String,
+    },
+}
+
+// FNV-1a, stable across builds unlike the std hasher
+fn fnv1a(input: &str) -> u64 {
+    input.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl Composition {
+    // Cache key for the composition of the emotes with these IDs
+    pub fn key(&self, ids: &[&str]) -> String {
+        match self {
+            Composition::Stack => ids.join("+"),
+            // Strips can be long, hash them so the key stays a valid filename
+            Composition::Strip { spacing, background } => {
+                format!("strip-{:016x}", fnv1a(&format!("{}|{spacing}|{background}", ids.join(","))))
+            },
+        }
+    }
+
+    // `resize` is ImageMagick geometry for the finished image
+    pub async fn render(&self, inputs: &[PathBuf], resize: Option<String>, work_dir: &Path, to: &Path) -> Result<(), Error> {
+        let output = Output { resize, to };
+
+        match self {
+            Composition::Stack => match inputs.split_first() {
+                Some((base, overlays)) => stack(base, overlays, work_dir, output).await,
+                None => Err(Error::InvalidRequest("Nothing to stack".to_owned())),
+            },
+            Composition::Strip { spacing, background } => strip(inputs, *spacing, background, work_dir, output).await,
+        }
+    }
+}
+
+// The coalesced frames of one input, each a full image of the same size
+struct Frames {
+    width: u32,
+    height: u32,
+    delays: Vec<u32>, // centiseconds
+    files: Vec<PathBuf>,
+}
+
+async fn convert(args: Vec<OsString>, context: &str) -> Result<String, Error> {
+    let output = Command::new("convert")
+        .args(args)
+        .output()
+        .await
+        .map_err(|x| Error::io(format!("Failed to run convert for {context}"), x))?;
+
+    if ! output.status.success() {
+        return Err(Error::Conversion(format!(
+            "{context}: {}",
+            String::from_utf8_lossy(&output.stderr),
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Writes every frame of `source` as a png into `work_dir`, named after `name`
+async fn split_frames(source: &Path, work_dir: &Path, name: &str) -> Result<Frames, Error> {
+    let info = convert(vec![
+        source.into(),
+        "-coalesce".into(),
+        "-format".into(),
+        "%T %w %h\n".into(),
+        "info:".into(),
+    ], name).await?;
+
+    let mut delays = Vec::new();
+    let (mut width, mut height) = (0, 0);
+
+    for line in info.lines() {
+        let mut fields = line.split_whitespace().map(|x| x.parse::<u32>().unwrap_or(0));
+        let delay = fields.next().unwrap_or(0);
+
+        width = width.max(fields.next().unwrap_or(0));
+        height = height.max(fields.next().unwrap_or(0));
+        delays.push(if delay < MIN_DELAY { DEFAULT_DELAY } else { delay });
+    }
+
+    if delays.is_empty() {
+        return Err(Error::Conversion(format!("{name}: No frames")));
+    }
+
+    convert(vec![
+        source.into(),
+        "-coalesce".into(),
+        "+adjoin".into(),
+        work_dir.join(format!("{name}_%d.png")).into(),
+    ], name).await?;
+
+    let files = (0..delays.len())
+        .map(|x| work_dir.join(format!("{name}_{x}.png")))
+        .collect();
+
+    Ok(Frames { width, height, delays, files })
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn frame_at(delays: &[u32], time: u64) -> usize {
+    let total: u64 = delays.iter().map(|&x| x as u64).sum();
+    let mut time = time % total;
+
+    for (index, &delay) in delays.iter().enumerate() {
+        if time < delay as u64 {
+            return index;
+        }
+
+        time -= delay as u64;
+    }
+
+    delays.len() - 1
+}
+
+// Output frames as (delay, frame index of every input). The output is cut wherever
+// any of the animated inputs changes frame, so each one keeps its own timing
+fn timeline(inputs: &[&Frames]) -> Vec<(u32, Vec<usize>)> {
+    let loops: Vec<u64> = inputs.iter()
+        .filter(|x| x.delays.len() > 1)
+        .map(|x| x.delays.iter().map(|&x| x as u64).sum())
+        .collect();
+
+    let longest = match loops.iter().max() {
+        Some(&longest) => longest,
+        None => return vec![(0, vec![0; inputs.len()])],
+    };
+
+    // Everything loops cleanly after the LCM of the loop lengths, as long as that's not too long
+    let length = loops.iter()
+        .try_fold(1, |acc, &x| Some(acc / gcd(acc, x) * x).filter(|&x| x <= MAX_DURATION))
+        .unwrap_or(longest);
+
+    let mut cuts = BTreeSet::from([0, length]);
+
+    for input in inputs.iter().filter(|x| x.delays.len() > 1) {
+        let mut time = 0;
+
+        for &delay in input.delays.iter().cycle() {
+            if time >= length {
+                break;
+            }
+
+            cuts.insert(time);
+            time += delay as u64;
+        }
+    }
+
+    let cuts: Vec<u64> = cuts.into_iter().collect();
+
+    cuts.windows(2)
+        .take(MAX_FRAMES)
+        .map(|x| {
+            let frames = inputs.iter().map(|input| frame_at(&input.delays, x[0])).collect();
+            ((x[1] - x[0]) as u32, frames)
+        })
+        .collect()
+}
+
+// ImageMagick doesn't go by every extension, apng and avif need the format spelled out
+pub fn output_path(path: &Path) -> OsString {
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_ascii_uppercase();
+    let mut output = OsString::from(format!("{extension}:"));
+
+    output.push(path);
+    output
+}
+
+struct Output<'a> {
+    resize: Option<String>,
+    to: &'a Path,
+}
+
+fn write_args(args: &mut Vec<OsString>, animated: bool, output: Output) {
+    if let Some(resize) = output.resize {
+        args.push("-resize".into());
+        args.push(resize.into());
+    }
+
+    if animated {
+        args.extend(["-set", "dispose", "Background", "-loop", "0"].map(OsString::from));
+    }
+
+    args.push(output_path(output.to));
+}
+
+// Draws every overlay centered on top of the base, the canvas grows to fit the largest layer
+async fn stack(base: &Path, overlays: &[PathBuf], work_dir: &Path, output: Output<'_>) -> Result<(), Error> {
+    let mut layers = vec![split_frames(base, work_dir, "base").await?];
+
+    for (index, overlay) in overlays.iter().enumerate() {
+        layers.push(split_frames(overlay, work_dir, &format!("overlay{index}")).await?);
+    }
+
+    let width = layers.iter().map(|x| x.width).max().unwrap_or(0);
+    let height = layers.iter().map(|x| x.height).max().unwrap_or(0);
+    let frames = timeline(&layers.iter().collect::<Vec<_>>());
+    let animated = frames.len() > 1;
+
+    let mut args: Vec<OsString> = ["-background", "none", "-gravity", "center"].map(OsString::from).into();
+
+    for (delay, indices) in &frames {
+        args.push("(".into());
+        args.push(layers[0].files[indices[0]].clone().into());
+        args.push("-extent".into());
+        args.push(format!("{width}x{height}").into());
+
+        for (layer, &index) in layers.iter().zip(indices).skip(1) {
+            args.push(layer.files[index].clone().into());
+            args.push("-composite".into());
+        }
+
+        args.push("-set".into());
+        args.push("delay".into());
+        args.push(delay.to_string().into());
+        args.push(")".into());
+    }
+
+    write_args(&mut args, animated, output);
+    convert(args, "stacked emote").await?;
+
+    Ok(())
+}
+
+// Lays the emotes out left to right, all scaled to the height of the tallest one
+async fn strip(emotes: &[PathBuf], spacing: u32, background: &str, work_dir: &Path, output: Output<'_>) -> Result<(), Error> {
+    let mut inputs = Vec::with_capacity(emotes.len());
+
+    for (index, emote) in emotes.iter().enumerate() {
+        inputs.push(split_frames(emote, work_dir, &format!("emote{index}")).await?);
+    }
+
+    let height = inputs.iter().map(|x| x.height).max().unwrap_or(0);
+    let frames = timeline(&inputs.iter().collect::<Vec<_>>());
+    let animated = frames.len() > 1;
+
+    let mut args: Vec<OsString> = ["-background", background].map(OsString::from).into();
+
+    for (delay, indices) in &frames {
+        args.push("(".into());
+
+        for (input, &index) in inputs.iter().zip(indices) {
+            args.push(input.files[index].clone().into());
+        }
+
+        args.push("-resize".into());
+        args.push(format!("x{height}").into());
+        args.push("+smush".into());
+        args.push(spacing.to_string().into());
+
+        // Transparent parts of the emotes should show the background too
+        if background != "none" {
+            args.push("-flatten".into());
+        }
+
+        args.push("-set".into());
+        args.push("delay".into());
+        args.push(delay.to_string().into());
+        args.push(")".into());
+    }
+
+    write_args(&mut args, animated, output);
+    convert(args, "emote strip").await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(delays: &[u32]) -> Frames {
+        Frames { width: 32, height: 32, delays: delays.to_vec(), files: Vec::new() }
+    }
+
+    fn duration(timeline: &[(u32, Vec<usize>)]) -> u64 {
+        timeline.iter().map(|(delay, _)| *delay as u64).sum()
+    }
+
+    #[test]
+    fn frame_at_wraps_around() {
+        let delays = [10, 20, 30];
+
+        let indices: Vec<usize> = [0, 9, 10, 29, 30, 59, 60, 75]
+            .into_iter()
+            .map(|x| frame_at(&delays, x))
+            .collect();
+
+        assert_eq!(indices, vec![0, 0, 1, 1, 2, 2, 0, 1]);
+    }
+
+    #[test]
+    fn static_inputs_make_one_frame() {
+        let (a, b) = (frames(&[10]), frames(&[0]));
+
+        assert_eq!(timeline(&[&a, &b]), vec![(0, vec![0, 0])]);
+    }
+
+    #[test]
+    fn loops_are_synced_over_their_lcm() {
+        let (still, a, b) = (frames(&[10]), frames(&[5, 5]), frames(&[4, 4, 4]));
+        let timeline = timeline(&[&still, &a, &b]);
+
+        assert_eq!(duration(&timeline), 60);
+        assert_eq!(timeline[..5], [
+            (4, vec![0, 0, 0]),
+            (1, vec![0, 0, 1]),
+            (3, vec![0, 1, 1]),
+            (2, vec![0, 1, 2]),
+            (2, vec![0, 0, 2]),
+        ]);
+
+        // A loop that ends right at MAX_DURATION is still synced
+        let (a, b) = (frames(&[750, 750]), frames(&[250, 250]));
+        assert_eq!(duration(&super::timeline(&[&a, &b])), MAX_DURATION);
+    }
+
+    #[test]
+    fn long_lcms_fall_back_to_the_longest_loop() {
+        let (a, b) = (frames(&[500, 500]), frames(&[700, 700]));
+
+        assert_eq!(timeline(&[&a, &b]), vec![
+            (500, vec![0, 0]),
+            (200, vec![1, 0]),
+            (300, vec![1, 1]),
+            (400, vec![0, 1]),
+        ]);
+    }
+
+    #[test]
+    fn frames_are_capped() {
+        // Every 2cs and every 3cs over 1500cs is 1000 cuts
+        let (a, b) = (frames(&[2; 750]), frames(&[3; 500]));
+
+        assert_eq!(timeline(&[&a, &b]).len(), MAX_FRAMES);
+    }
+
+    #[test]
+    fn composition_keys() {
+        assert_eq!(Composition::Stack.key(&["abc", "def"]), "abc+def");
+        assert_eq!(fnv1a("a"), 0xaf63dc4c8601ec8c);
+
+        let strip = |spacing, background: &str| Composition::Strip { spacing, background: background.to_owned() };
+        let key = strip(4, "none").key(&["abc", "def"]);
+
+        assert_eq!(key, format!("strip-{:016x}", fnv1a("abc,def|4|none")));
+        assert_eq!(key, strip(4, "none").key(&["abc", "def"]));
+        assert_ne!(key, strip(4, "none").key(&["def", "abc"]));
+        assert_ne!(key, strip(8, "none").key(&["abc", "def"]));
+        assert_ne!(key, strip(4, "#ff8800").key(&["abc", "def"]));
+    }
+}
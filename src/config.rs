@@ -0,0 +1,661 @@
+use std::{env, fs, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    compose::{self, StripConfig},
+    cors::CorsConfig,
+    error::Error,
+    http::RequestLimits,
+    listener::{self, ListenAddr},
+    logging::{self, LogFormat},
+    proxy::IpNet,
+    rate_limit::RateLimitConfig,
+    tls::TlsConfig,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "./config.toml";
+
+// Every env variable we read is namespaced, names like LISTEN or CACHE_DIR
+// are too easily set for something else
+const ENV_PREFIX: &str = "THIRDPARTYTHING_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Gif,
+    // What 7tv has, served without any conversion
+    Webp,
+    Png,
+    Avif,
+    Apng,
+}
+
+const OUTPUT_FORMATS: [OutputFormat; 5] = [
+    OutputFormat::Gif,
+    OutputFormat::Webp,
+    OutputFormat::Png,
+    OutputFormat::Avif,
+    OutputFormat::Apng,
+];
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Gif => "gif",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Png => "png",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Apng => "apng",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            OutputFormat::Gif => "image/gif",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Apng => "image/apng",
+        }
+    }
+
+    // Plain png can't move and apng of a single frame is just png
+    pub fn for_emote(self, animated: bool) -> OutputFormat {
+        match (self, animated) {
+            (OutputFormat::Png, true) => OutputFormat::Apng,
+            (OutputFormat::Apng, false) => OutputFormat::Png,
+            (format, _) => format,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        OUTPUT_FORMATS.into_iter()
+            .find(|x| x.extension() == input)
+            .ok_or_else(|| format!("unknown format {input}, expected gif, webp, png, avif or apng"))
+    }
+}
+
+// The scales 7tv has every emote in, 1x is 32px high for most emotes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum EmoteSize {
+    #[serde(rename = "1x")]
+    X1,
+    #[serde(rename = "2x")]
+    X2,
+    #[serde(rename = "3x")]
+    X3,
+    #[serde(rename = "4x")]
+    X4,
+}
+
+const EMOTE_SIZES: [EmoteSize; 4] = [EmoteSize::X1, EmoteSize::X2, EmoteSize::X3, EmoteSize::X4];
+
+impl EmoteSize {
+    fn scale(self) -> i32 {
+        EMOTE_SIZES.iter().position(|x| *x == self).unwrap_or(0) as i32 + 1
+    }
+
+    // The smallest size that's at least this many pixels high, 7tv's 1x is 32px
+    pub fn covering(pixels: u32) -> EmoteSize {
+        EMOTE_SIZES[(pixels.saturating_sub(1) / 32).min(3) as usize]
+    }
+
+    // Every size, closest to this one first. Of two equally close sizes the larger one wins
+    pub fn by_closeness(self) -> Vec<EmoteSize> {
+        let mut sizes = EMOTE_SIZES.to_vec();
+        sizes.sort_by_key(|x| ((x.scale() - self.scale()).abs(), -x.scale()));
+        sizes
+    }
+}
+
+impl std::fmt::Display for EmoteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x", self.scale())
+    }
+}
+
+impl FromStr for EmoteSize {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        EMOTE_SIZES.into_iter()
+            .find(|x| x.to_string() == input)
+            .ok_or_else(|| format!("unknown size {input}, expected 1x, 2x, 3x or 4x"))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // TCP addresses and unix:/path sockets, ignored when socket activated by systemd
+    #[serde(deserialize_with = "listener::deserialize_listen")]
+    pub listen: Vec<ListenAddr>,
+    // Whether [::] only accepts IPv6, otherwise it's dual-stack
+    pub ipv6_only: bool,
+    // Octal permissions for Unix sockets we create
+    pub unix_socket_mode: String,
+    // Peers whose X-Forwarded-For, Forwarded and PROXY protocol headers are believed
+    pub trusted_proxies: Vec<IpNet>,
+    // Every connection has to open with a PROXY protocol v1 or v2 header
+    pub proxy_protocol: bool,
+    pub cache_dir: PathBuf,
+    // RUST_LOG style directives like "info" or "warn,access=info"
+    pub log_level: String,
+    pub log_format: LogFormat,
+
+    // seconds
+    pub user_emote_reload_cooldown: u64,
+    pub keyword_max_age: u64,
+    pub idle_timeout: u64,
+    // From the first byte of a request until it's fully read, answered with a 408 otherwise
+    pub head_timeout: u64,
+    // Twitch ID, 7tv lookup and pull together, answered with a 504 otherwise
+    pub upstream_timeout: u64,
+    // A single call to Twitch or 7tv, so a hung one leaves time for the fallbacks
+    pub upstream_request_timeout: u64,
+    pub write_timeout: u64,
+    pub shutdown_deadline: u64,
+
+    pub seventv_api_url: String,
+    pub seventv_cdn_url: String,
+    pub twitch_api_url: String,
+    pub twitch_auth_url: String,
+
+    pub actor_channel_capacity: usize,
+    // convert processes running at once, emote conversions and compositions together
+    pub max_conversions: usize,
+    // Connections over this are answered with a 503 right away
+    pub max_connections: usize,
+    pub max_requests_per_connection: usize,
+    pub max_head_size: usize,
+    pub max_body_size: usize,
+    // Over TLS through ALPN, and as h2c on plain connections that open with the HTTP/2 preface
+    pub http2: bool,
+    // Prometheus text format on /metrics
+    pub metrics: bool,
+
+    // Size fetched from the 7tv CDN when the request doesn't ask for one
+    pub emote_size: EmoteSize,
+    // What animated emotes are served as when neither the extension nor Accept picks
+    // a format, static ones are webp then
+    pub default_format: OutputFormat,
+
+    pub cors: CorsConfig,
+    pub tls: TlsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub strip: StripConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let limits = RequestLimits::default();
+
+        Self {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 8080)))],
+            ipv6_only: false,
+            unix_socket_mode: "660".to_owned(),
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            cache_dir: PathBuf::from("./emotes"),
+            log_level: "info".to_owned(),
+            log_format: LogFormat::Text,
+            user_emote_reload_cooldown: 10 * 60,
+            keyword_max_age: 5 * 60,
+            idle_timeout: 15,
+            head_timeout: 10,
+            upstream_timeout: 30,
+            upstream_request_timeout: 10,
+            write_timeout: 30,
+            shutdown_deadline: 30,
+            seventv_api_url: "https://7tv.io/v3".to_owned(),
+            seventv_cdn_url: "https://cdn.7tv.app".to_owned(),
+            twitch_api_url: "https://api.twitch.tv/helix".to_owned(),
+            twitch_auth_url: "https://id.twitch.tv/oauth2".to_owned(),
+            actor_channel_capacity: 50,
+            max_conversions: 4,
+            max_connections: 1024,
+            max_requests_per_connection: 100,
+            max_head_size: limits.max_head_size,
+            max_body_size: limits.max_body_size,
+            http2: true,
+            metrics: true,
+            emote_size: EmoteSize::X4,
+            default_format: OutputFormat::Gif,
+            cors: CorsConfig::default(),
+            tls: TlsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            strip: StripConfig::default(),
+        }
+    }
+}
+
+type Setter = fn(&mut Config, &str) -> Result<(), String>;
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: ToString,
+{
+    value.parse::<T>().map_err(|x| x.to_string())
+}
+
+// Comma separated, empty entries are skipped
+fn parse_list<T: FromStr>(value: &str) -> Result<Vec<T>, String>
+where
+    T::Err: ToString,
+{
+    value.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(parse)
+        .collect()
+}
+
+// Every setting that can be overridden, as (CLI flag, env variable without ENV_PREFIX, setter)
+const OVERRIDES: &[(&str, &str, Setter)] = &[
+    ("--listen", "LISTEN", |c, v| { c.listen = parse_list(v)?; Ok(()) }),
+    ("--ipv6-only", "IPV6_ONLY", |c, v| { c.ipv6_only = parse(v)?; Ok(()) }),
+    ("--unix-socket-mode", "UNIX_SOCKET_MODE", |c, v| { c.unix_socket_mode = v.to_owned(); Ok(()) }),
+    ("--trusted-proxies", "TRUSTED_PROXIES", |c, v| { c.trusted_proxies = parse_list(v)?; Ok(()) }),
+    ("--proxy-protocol", "PROXY_PROTOCOL", |c, v| { c.proxy_protocol = parse(v)?; Ok(()) }),
+    ("--cache-dir", "CACHE_DIR", |c, v| { c.cache_dir = PathBuf::from(v); Ok(()) }),
+    ("--log-level", "LOG_LEVEL", |c, v| { c.log_level = v.to_owned(); Ok(()) }),
+    ("--log-format", "LOG_FORMAT", |c, v| { c.log_format = parse(v)?; Ok(()) }),
+    ("--user-emote-reload-cooldown", "USER_EMOTE_RELOAD_COOLDOWN", |c, v| { c.user_emote_reload_cooldown = parse(v)?; Ok(()) }),
+    ("--keyword-max-age", "KEYWORD_MAX_AGE", |c, v| { c.keyword_max_age = parse(v)?; Ok(()) }),
+    ("--idle-timeout", "IDLE_TIMEOUT", |c, v| { c.idle_timeout = parse(v)?; Ok(()) }),
+    ("--head-timeout", "HEAD_TIMEOUT", |c, v| { c.head_timeout = parse(v)?; Ok(()) }),
+    ("--upstream-timeout", "UPSTREAM_TIMEOUT", |c, v| { c.upstream_timeout = parse(v)?; Ok(()) }),
+    ("--upstream-request-timeout", "UPSTREAM_REQUEST_TIMEOUT", |c, v| { c.upstream_request_timeout = parse(v)?; Ok(()) }),
+    ("--write-timeout", "WRITE_TIMEOUT", |c, v| { c.write_timeout = parse(v)?; Ok(()) }),
+    ("--shutdown-deadline", "SHUTDOWN_DEADLINE", |c, v| { c.shutdown_deadline = parse(v)?; Ok(()) }),
+    ("--seventv-api-url", "SEVENTV_API_URL", |c, v| { c.seventv_api_url = v.to_owned(); Ok(()) }),
+    ("--seventv-cdn-url", "SEVENTV_CDN_URL", |c, v| { c.seventv_cdn_url = v.to_owned(); Ok(()) }),
+    ("--twitch-api-url", "TWITCH_API_URL", |c, v| { c.twitch_api_url = v.to_owned(); Ok(()) }),
+    ("--twitch-auth-url", "TWITCH_AUTH_URL", |c, v| { c.twitch_auth_url = v.to_owned(); Ok(()) }),
+    ("--actor-channel-capacity", "ACTOR_CHANNEL_CAPACITY", |c, v| { c.actor_channel_capacity = parse(v)?; Ok(()) }),
+    ("--max-conversions", "MAX_CONVERSIONS", |c, v| { c.max_conversions = parse(v)?; Ok(()) }),
+    ("--max-connections", "MAX_CONNECTIONS", |c, v| { c.max_connections = parse(v)?; Ok(()) }),
+    ("--max-requests-per-connection", "MAX_REQUESTS_PER_CONNECTION", |c, v| { c.max_requests_per_connection = parse(v)?; Ok(()) }),
+    ("--max-head-size", "MAX_HEAD_SIZE", |c, v| { c.max_head_size = parse(v)?; Ok(()) }),
+    ("--max-body-size", "MAX_BODY_SIZE", |c, v| { c.max_body_size = parse(v)?; Ok(()) }),
+    ("--http2", "HTTP2", |c, v| { c.http2 = parse(v)?; Ok(()) }),
+    ("--metrics", "METRICS", |c, v| { c.metrics = parse(v)?; Ok(()) }),
+    ("--emote-size", "EMOTE_SIZE", |c, v| { c.emote_size = parse(v)?; Ok(()) }),
+    ("--default-format", "DEFAULT_FORMAT", |c, v| { c.default_format = parse(v)?; Ok(()) }),
+    ("--cors-allow-origins", "CORS_ALLOW_ORIGINS", |c, v| { c.cors.allow_origins = parse_list(v)?; Ok(()) }),
+    ("--cors-allow-methods", "CORS_ALLOW_METHODS", |c, v| { c.cors.allow_methods = v.to_owned(); Ok(()) }),
+    ("--cors-allow-headers", "CORS_ALLOW_HEADERS", |c, v| { c.cors.allow_headers = v.to_owned(); Ok(()) }),
+    ("--cors-expose-headers", "CORS_EXPOSE_HEADERS", |c, v| { c.cors.expose_headers = v.to_owned(); Ok(()) }),
+    ("--cors-max-age", "CORS_MAX_AGE", |c, v| { c.cors.max_age = parse(v)?; Ok(()) }),
+    ("--tls-cert", "TLS_CERT", |c, v| { c.tls.cert_path = Some(PathBuf::from(v)); Ok(()) }),
+    ("--tls-key", "TLS_KEY", |c, v| { c.tls.key_path = Some(PathBuf::from(v)); Ok(()) }),
+    ("--tls-listen", "TLS_LISTEN", |c, v| { c.tls.listen = parse_list(v)?; Ok(()) }),
+    ("--tls-redirect-http", "TLS_REDIRECT_HTTP", |c, v| { c.tls.redirect_http = parse(v)?; Ok(()) }),
+    ("--rate-limit-requests-per-minute", "RATE_LIMIT_REQUESTS_PER_MINUTE", |c, v| { c.rate_limit.requests_per_minute = parse(v)?; Ok(()) }),
+    ("--rate-limit-request-burst", "RATE_LIMIT_REQUEST_BURST", |c, v| { c.rate_limit.request_burst = parse(v)?; Ok(()) }),
+    ("--rate-limit-upstream-per-minute", "RATE_LIMIT_UPSTREAM_PER_MINUTE", |c, v| { c.rate_limit.upstream_per_minute = parse(v)?; Ok(()) }),
+    ("--rate-limit-upstream-burst", "RATE_LIMIT_UPSTREAM_BURST", |c, v| { c.rate_limit.upstream_burst = parse(v)?; Ok(()) }),
+    ("--strip-spacing", "STRIP_SPACING", |c, v| { c.strip.spacing = parse(v)?; Ok(()) }),
+    ("--strip-background", "STRIP_BACKGROUND", |c, v| { c.strip.background = v.to_owned(); Ok(()) }),
+];
+
+pub fn usage() -> String {
+    let mut usage = "Usage: thirdpartything [--config <path>] [options]\n\nOptions (also settable through the listed env variable):\n".to_owned();
+
+    for (flag, env_name, _) in OVERRIDES {
+        usage.push_str(&format!("  {flag:<32} {ENV_PREFIX}{env_name}\n"));
+    }
+
+    usage
+}
+
+impl Config {
+    // Defaults, then the TOML file, then env variables, then CLI flags
+    pub fn load(args: &[String]) -> Result<Config, Error> {
+        Self::load_with_env(args, |name| env::var(name).ok())
+    }
+
+    fn load_with_env(args: &[String], get_env: impl Fn(&str) -> Option<String>) -> Result<Config, Error> {
+        let invalid = |message: String| Error::Config(message);
+
+        let mut flags = Vec::new();
+        let mut config_path = get_env(&format!("{ENV_PREFIX}CONFIG_FILE"));
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_owned())),
+                None => (arg.as_str(), None),
+            };
+
+            let value = match inline_value {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| invalid(format!("Missing value for {flag}")))?,
+            };
+
+            if flag == "--config" {
+                config_path = Some(value);
+                continue;
+            }
+
+            let setter = OVERRIDES.iter()
+                .find(|(name, _, _)| *name == flag)
+                .map(|(_, _, setter)| *setter)
+                .ok_or_else(|| invalid(format!("Unknown flag {flag}\n\n{}", usage())))?;
+
+            flags.push((flag.to_owned(), setter, value));
+        }
+
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None if fs::metadata(DEFAULT_CONFIG_PATH).is_ok() => Self::from_file(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+
+        for (_, env_name, setter) in OVERRIDES {
+            if let Some(value) = get_env(&format!("{ENV_PREFIX}{env_name}")) {
+                setter(&mut config, &value).map_err(|x| invalid(format!("Invalid {ENV_PREFIX}{env_name}: {x}")))?;
+            }
+        }
+
+        for (flag, setter, value) in flags {
+            setter(&mut config, &value).map_err(|x| invalid(format!("Invalid {flag}: {x}")))?;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Config, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|x| Error::io(format!("Failed to read config file {path}"), x))?;
+
+        toml::from_str(&content)
+            .map_err(|x| Error::Config(format!("Invalid config file {path}: {x}")))
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: &str| Err(Error::Config(message.to_owned()));
+
+        if self.listen.is_empty() {
+            return invalid("listen needs at least one address");
+        }
+
+        if u32::from_str_radix(&self.unix_socket_mode, 8).map(|x| x > 0o777).unwrap_or(true) {
+            return invalid("unix_socket_mode must be octal permissions like 660");
+        }
+
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            return invalid("tls.cert_path and tls.key_path have to be set together");
+        }
+
+        if self.tls.enabled() && self.tls.listen.is_empty() {
+            return invalid("tls.listen needs at least one address");
+        }
+
+        if self.tls.redirect_http && ! self.tls.enabled() {
+            return invalid("tls.redirect_http needs tls.cert_path and tls.key_path");
+        }
+
+        if let Err(err) = logging::parse_filter(&self.log_level) {
+            return invalid(&format!("log_level is invalid: {err}"));
+        }
+
+        let urls = [
+            ("seventv_api_url", &self.seventv_api_url),
+            ("seventv_cdn_url", &self.seventv_cdn_url),
+            ("twitch_api_url", &self.twitch_api_url),
+            ("twitch_auth_url", &self.twitch_auth_url),
+        ];
+
+        for (name, url) in urls {
+            if ! url.starts_with("http://") && ! url.starts_with("https://") {
+                return invalid(&format!("{name} must be an http(s) URL"));
+            }
+        }
+
+        let non_zero = [
+            ("actor_channel_capacity", self.actor_channel_capacity),
+            ("max_conversions", self.max_conversions),
+            ("max_connections", self.max_connections),
+            ("max_requests_per_connection", self.max_requests_per_connection),
+            ("max_head_size", self.max_head_size),
+            ("idle_timeout", self.idle_timeout as usize),
+            ("head_timeout", self.head_timeout as usize),
+            ("upstream_timeout", self.upstream_timeout as usize),
+            ("upstream_request_timeout", self.upstream_request_timeout as usize),
+            ("write_timeout", self.write_timeout as usize),
+        ];
+
+        for (name, value) in non_zero {
+            if value == 0 {
+                return invalid(&format!("{name} must be greater than 0"));
+            }
+        }
+
+        if self.upstream_request_timeout > self.upstream_timeout {
+            return invalid("upstream_request_timeout can't be over upstream_timeout");
+        }
+
+        let rates = [
+            ("rate_limit.requests_per_minute", self.rate_limit.requests_per_minute),
+            ("rate_limit.upstream_per_minute", self.rate_limit.upstream_per_minute),
+        ];
+
+        for (name, value) in rates {
+            if ! value.is_finite() || value < 0.0 {
+                return invalid(&format!("{name} must be 0 or more"));
+            }
+        }
+
+        if self.strip.spacing > compose::MAX_SPACING {
+            return invalid(&format!("strip.spacing can't be over {}", compose::MAX_SPACING));
+        }
+
+        if let Err(err) = compose::parse_background(&self.strip.background) {
+            return invalid(&format!("strip.background is invalid: {err}"));
+        }
+
+        if self.cors.allow_origins.is_empty() {
+            return invalid("cors.allow_origins can't be empty, use [\"*\"] to allow any origin");
+        }
+
+        fs::create_dir_all(&self.cache_dir)
+            .map_err(|x| Error::io(format!("Failed to create cache_dir {}", self.cache_dir.display()), x))?;
+
+        Ok(())
+    }
+
+    pub fn request_limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_head_size: self.max_head_size,
+            max_body_size: self.max_body_size,
+        }
+    }
+
+    pub fn unix_socket_mode(&self) -> u32 {
+        u32::from_str_radix(&self.unix_socket_mode, 8).unwrap_or(0o660)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn head_timeout(&self) -> Duration {
+        Duration::from_secs(self.head_timeout)
+    }
+
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_secs(self.upstream_timeout)
+    }
+
+    pub fn upstream_request_timeout(&self) -> Duration {
+        Duration::from_secs(self.upstream_request_timeout)
+    }
+
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout)
+    }
+
+    pub fn shutdown_deadline(&self) -> Duration {
+        Duration::from_secs(self.shutdown_deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn args(input: &[&str]) -> Vec<String> {
+        input.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("thirdpartything-{}-{name}", std::process::id()))
+    }
+
+    fn error_message(result: Result<impl std::fmt::Debug, Error>) -> String {
+        match result {
+            Err(Error::Config(message)) => message,
+            other => panic!("expected a config error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn covering_sizes() {
+        use EmoteSize::*;
+
+        let sizes: Vec<EmoteSize> = [0, 1, 32, 33, 64, 65, 96, 97, 128, 5000]
+            .into_iter()
+            .map(EmoteSize::covering)
+            .collect();
+
+        assert_eq!(sizes, vec![X1, X1, X1, X2, X2, X3, X3, X4, X4, X4]);
+    }
+
+    #[test]
+    fn sizes_by_closeness() {
+        use EmoteSize::*;
+
+        // Ties go to the larger size, a scaled down emote looks better than a scaled up one
+        assert_eq!(X1.by_closeness(), vec![X1, X2, X3, X4]);
+        assert_eq!(X2.by_closeness(), vec![X2, X3, X1, X4]);
+        assert_eq!(X3.by_closeness(), vec![X3, X4, X2, X1]);
+        assert_eq!(X4.by_closeness(), vec![X4, X3, X2, X1]);
+
+        assert_eq!("2x".parse::<EmoteSize>(), Ok(X2));
+        assert_eq!(X2.to_string(), "2x");
+        assert!("2".parse::<EmoteSize>().is_err());
+    }
+
+    #[test]
+    fn overrides_apply_in_order() {
+        let cache_dir = temp_path("order-cache");
+        let config_path = temp_path("order.toml");
+        fs::write(&config_path, format!(
+            "cache_dir = {:?}\nkeyword_max_age = 1\nidle_timeout = 2\nhead_timeout = 3\n",
+            cache_dir.display().to_string(),
+        )).unwrap();
+
+        let vars = HashMap::from([
+            ("THIRDPARTYTHING_CONFIG_FILE".to_owned(), config_path.display().to_string()),
+            ("THIRDPARTYTHING_IDLE_TIMEOUT".to_owned(), "20".to_owned()),
+            ("THIRDPARTYTHING_HEAD_TIMEOUT".to_owned(), "30".to_owned()),
+            // Unprefixed names belong to someone else
+            ("WRITE_TIMEOUT".to_owned(), "40".to_owned()),
+        ]);
+
+        let config = Config::load_with_env(&args(&["--head-timeout", "300"]), |x| vars.get(x).cloned()).unwrap();
+
+        assert_eq!(config.keyword_max_age, 1);
+        assert_eq!(config.idle_timeout, 20);
+        assert_eq!(config.head_timeout, 300);
+        assert_eq!(config.write_timeout, Config::default().write_timeout);
+
+        let _ = fs::remove_file(config_path);
+        let _ = fs::remove_dir(cache_dir);
+    }
+
+    #[test]
+    fn flag_forms() {
+        let cache_dir = temp_path("flags-cache");
+        let config_path = temp_path("flags.toml");
+        fs::write(&config_path, "").unwrap();
+
+        let load = |input: &[&str]| {
+            let mut input = args(input);
+            input.extend(args(&["--config", &config_path.display().to_string()]));
+            input.extend(args(&["--cache-dir", &cache_dir.display().to_string()]));
+
+            Config::load_with_env(&input, |_| None)
+        };
+
+        let config = load(&["--metrics=false", "--http2", "false", "--strip-background=#ff0000"]).unwrap();
+        assert!(! config.metrics);
+        assert!(! config.http2);
+        assert_eq!(config.strip.background, "#ff0000");
+
+        // Only the first '=' splits
+        let config = load(&["--unix-socket-mode=600", "--listen=unix:/tmp/a=b.sock"]).unwrap();
+        assert_eq!(config.unix_socket_mode, "600");
+        assert!(matches!(&config.listen[..], [ListenAddr::Unix(path)] if path.ends_with("a=b.sock")));
+
+        assert!(error_message(load(&["--nope", "1"])).starts_with("Unknown flag --nope"));
+        assert!(error_message(load(&["--metrics", "maybe"])).starts_with("Invalid --metrics"));
+        assert_eq!(error_message(Config::load_with_env(&args(&["--metrics"]), |_| None)), "Missing value for --metrics");
+
+        let _ = fs::remove_file(config_path);
+        let _ = fs::remove_dir(cache_dir);
+    }
+
+    #[test]
+    fn validation_errors() {
+        let cache_dir = temp_path("validate-cache");
+        let config_path = temp_path("validate.toml");
+        fs::write(&config_path, "").unwrap();
+
+        let load = |flag: &str, value: &str| {
+            let input = args(&[
+                "--config", &config_path.display().to_string(),
+                "--cache-dir", &cache_dir.display().to_string(),
+                flag, value,
+            ]);
+
+            Config::load_with_env(&input, |_| None)
+        };
+
+        assert!(load("--metrics", "true").is_ok());
+
+        let cases = [
+            ("--listen", "", "listen needs at least one address"),
+            ("--unix-socket-mode", "999", "unix_socket_mode must be octal"),
+            ("--tls-cert", "cert.pem", "tls.cert_path and tls.key_path have to be set together"),
+            ("--tls-redirect-http", "true", "tls.redirect_http needs"),
+            ("--log-level", "thirdpartything=loud", "log_level is invalid"),
+            ("--twitch-api-url", "ftp://example.com", "twitch_api_url must be an http(s) URL"),
+            ("--max-conversions", "0", "max_conversions must be greater than 0"),
+            ("--upstream-request-timeout", "31", "upstream_request_timeout can't be over upstream_timeout"),
+            ("--rate-limit-upstream-per-minute", "NaN", "rate_limit.upstream_per_minute must be 0 or more"),
+            ("--cors-allow-origins", ",", "cors.allow_origins can't be empty"),
+        ];
+
+        for (flag, value, expected) in cases {
+            let message = error_message(load(flag, value));
+            assert!(message.starts_with(expected), "{flag} {value:?}: {message:?}");
+        }
+
+        let _ = fs::remove_file(config_path);
+        let _ = fs::remove_dir(cache_dir);
+    }
+}
@@ -0,0 +1,77 @@
+use serde::Deserialize;
+
+use crate::http::{HttpRequest, HttpResponse, HttpStatus};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // Either ["*"] or the exact origins that are allowed
+    pub allow_origins: Vec<String>,
+    pub allow_methods: String,
+    pub allow_headers: String,
+    pub expose_headers: String,
+    pub max_age: u64, // seconds
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allow_origins: vec!["*".to_owned()],
+            allow_methods: "GET, HEAD, OPTIONS".to_owned(),
+            allow_headers: "*".to_owned(),
+            expose_headers: "Content-Length, Content-Type".to_owned(),
+            max_age: 24 * 60 * 60,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn allowed_origin(&self, request: &HttpRequest) -> Option<String> {
+        if self.allow_origins.iter().any(|x| x == "*") {
+            return Some("*".to_owned());
+        }
+
+        let origin = request.header("Origin")?;
+
+        self.allow_origins
+            .iter()
+            .find(|x| x.as_str() == origin)
+            .cloned()
+    }
+
+    pub fn apply(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        let wildcard = self.allow_origins.iter().any(|x| x == "*");
+
+        if ! wildcard {
+            // The header below depends on the request's origin
+            response.add_vary("Origin");
+        }
+
+        if let Some(origin) = self.allowed_origin(request) {
+            response.set_header("Access-Control-Allow-Origin", &origin);
+            response.set_header("Access-Control-Expose-Headers", &self.expose_headers);
+        }
+    }
+
+    pub fn preflight(&self, request: &HttpRequest) -> HttpResponse {
+        let mut response = HttpResponse::new(HttpStatus::NoContent);
+        response.set_header("Allow", &self.allow_methods);
+
+        let is_preflight = request.header("Origin").is_some()
+            && request.header("Access-Control-Request-Method").is_some();
+
+        if is_preflight && self.allowed_origin(request).is_some() {
+            let allow_headers = match (self.allow_headers.as_str(), request.header("Access-Control-Request-Headers")) {
+                // Browsers don't honor a wildcard on credentialed requests, echoing is equivalent
+                ("*", Some(requested)) => requested.to_owned(),
+                (allowed, _) => allowed.to_owned(),
+            };
+
+            response.set_header("Access-Control-Allow-Methods", &self.allow_methods);
+            response.set_header("Access-Control-Allow-Headers", &allow_headers);
+            response.set_header("Access-Control-Max-Age", &self.max_age.to_string());
+        }
+
+        response
+    }
+}
@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
-use tokio::sync::{oneshot, mpsc};
+use tokio::{sync::{oneshot, mpsc}, task::JoinHandle};
+use tracing::debug;
 
-use crate::{seventv::{get_twitch_user_emote_set, get_most_popular_emote, SevenUserEmote}, utils::now_secs};
+use crate::{seventv::{SevenTvClient, SevenUserEmote}, utils::now_secs, error::Error, config::Config, metrics::METRICS, rate_limit::UpstreamBudget};
 
 struct TwitchUserEmoteMap {
     last_updated: u64, // seconds
@@ -14,31 +15,51 @@ struct TwitchUserEmoteMap {
 
 struct EmoteManager {
     receiver: mpsc::Receiver<EmoteManagerMessage>,
+    seventv_client: Arc<SevenTvClient>,
+    // in seconds
+    user_emote_reload_cooldown: u64,
     twitch_id_emotes_map: HashMap<String, TwitchUserEmoteMap>,
     popular_emote_map: HashMap<String, SevenUserEmote>,
+    emote_id_map: HashMap<String, SevenUserEmote>,
 }
 
+#[allow(clippy::enum_variant_names)]
 enum EmoteManagerMessage {
     GetUserEmoteByKeyword {
-        sender_cb: oneshot::Sender<Result<SevenUserEmote, String>>,
+        sender_cb: oneshot::Sender<Result<SevenUserEmote, Error>>,
         twitch_id: String,
         emote_keyword: String,
+        budget: UpstreamBudget,
     },
     GetPopularEmoteByKeyword {
-        sender_cb: oneshot::Sender<Result<SevenUserEmote, String>>,
+        sender_cb: oneshot::Sender<Result<SevenUserEmote, Error>>,
         emote_keyword: String,
+        budget: UpstreamBudget,
+    },
+    GetEmoteById {
+        sender_cb: oneshot::Sender<Result<SevenUserEmote, Error>>,
+        emote_id: String,
+        budget: UpstreamBudget,
+    },
+    // Answered as soon as everything queued before it is done
+    Ping {
+        sender_cb: oneshot::Sender<()>,
     },
 }
 
 impl EmoteManager {
-    // in seconds
-    const USER_EMOTE_RELOAD_COOLDOWN: u64 = 10 * 60;
-                                                     
-    fn new(receiver: mpsc::Receiver<EmoteManagerMessage>) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<EmoteManagerMessage>,
+        seventv_client: Arc<SevenTvClient>,
+        user_emote_reload_cooldown: u64,
+    ) -> Self {
         Self {
             receiver,
+            seventv_client,
+            user_emote_reload_cooldown,
             twitch_id_emotes_map: HashMap::new(),
             popular_emote_map: HashMap::new(),
+            emote_id_map: HashMap::new(),
         }
     }
     
@@ -46,21 +67,27 @@ impl EmoteManager {
         &mut self,
         twitch_id: &str,
         reload: bool,
-    ) -> Result<bool, String> {
+        budget: &UpstreamBudget,
+    ) -> Result<bool, Error> {
         let existing = self.twitch_id_emotes_map.get(twitch_id);
 
         if existing.is_some() && ! reload {
             return Ok(false);
         }
         
+        // Misses only trigger a reload once the cooldown has passed
         if let Some(map) = existing {
-            if now_secs() > map.last_updated + Self::USER_EMOTE_RELOAD_COOLDOWN {
+            if now_secs() < map.last_updated + self.user_emote_reload_cooldown {
                 return Ok(false);
             }
         }
 
-        let emote_set = get_twitch_user_emote_set(&twitch_id).await?;
-        println!("Emote set {:?}", emote_set);
+        budget.spend()?;
+        let result = if existing.is_some() { "refresh" } else { "miss" };
+        METRICS.emote_cache.inc(&[("cache", "user_emotes"), ("result", result)]);
+
+        let emote_set = self.seventv_client.get_twitch_user_emote_set(twitch_id).await?;
+        debug!(twitch_id, emotes = emote_set.emotes.len(), "Loaded 7tv emote set");
         let emote_map = TwitchUserEmoteMap {
             last_updated: now_secs(),
             map: HashMap::from_iter(
@@ -80,40 +107,47 @@ impl EmoteManager {
         &mut self,
         twitch_id: &str,
         emote_keyword: &str,
-    ) -> Result<SevenUserEmote, String> {
-        self.load_user_emotes(twitch_id, false).await?;
+        budget: &UpstreamBudget,
+    ) -> Result<SevenUserEmote, Error> {
+        let loaded = self.load_user_emotes(twitch_id, false, budget).await?;
 
         let map = match self.twitch_id_emotes_map.get(twitch_id) {
-            Some(TwitchUserEmoteMap { map, last_updated }) => map,
-            None => return Err("7tv: User not found".to_owned()),
+            Some(TwitchUserEmoteMap { map, .. }) => map,
+            None => return Err(Error::NotFound("7tv: User not found".to_owned())),
         };
 
-        if ! map.contains_key(emote_keyword) {
-            drop(map);
-            if ! self.load_user_emotes(twitch_id, true).await? {
-                return Err("Emote not found".to_owned());
-            }
+        if ! loaded && map.contains_key(emote_keyword) {
+            METRICS.emote_cache.inc(&[("cache", "user_emotes"), ("result", "hit")]);
+        }
+
+        if ! map.contains_key(emote_keyword) && ! self.load_user_emotes(twitch_id, true, budget).await? {
+            return Err(Error::NotFound("Emote not found".to_owned()));
         }
 
         // TODO: Refactor following duplicate code
         let map = match self.twitch_id_emotes_map.get(twitch_id) {
-            Some(TwitchUserEmoteMap { map, last_updated }) => map,
-            None => return Err("7tv: User not found".to_owned()),
+            Some(TwitchUserEmoteMap { map, .. }) => map,
+            None => return Err(Error::NotFound("7tv: User not found".to_owned())),
         };
 
         if let Some(emote) = map.get(emote_keyword) {
             return Ok(emote.clone());
         }
 
-        Err("Emote not found".to_owned())
+        Err(Error::NotFound("Emote not found".to_owned()))
     }
 
     // Gets most popular emote by keyword
-    async fn get_emote(&mut self, emote_keyword: &str) -> Result<SevenUserEmote, String> {
+    async fn get_emote(&mut self, emote_keyword: &str, budget: &UpstreamBudget) -> Result<SevenUserEmote, Error> {
         let emote_id = match self.popular_emote_map.get(emote_keyword) {
-            Some(emote) => emote.clone(),
+            Some(emote) => {
+                METRICS.emote_cache.inc(&[("cache", "popular_emotes"), ("result", "hit")]);
+                emote.clone()
+            },
             None => {
-                let emote = get_most_popular_emote(emote_keyword).await?;
+                budget.spend()?;
+                METRICS.emote_cache.inc(&[("cache", "popular_emotes"), ("result", "miss")]);
+                let emote = self.seventv_client.get_most_popular_emote(emote_keyword).await?;
                 self.popular_emote_map.insert(emote_keyword.to_owned(), emote.clone());
 
                 emote
@@ -123,22 +157,51 @@ impl EmoteManager {
         Ok(emote_id)
     }
 
+    // Emotes are immutable per ID so these never need to be reloaded
+    async fn get_emote_by_id(&mut self, emote_id: &str, budget: &UpstreamBudget) -> Result<SevenUserEmote, Error> {
+        if let Some(emote) = self.emote_id_map.get(emote_id) {
+            METRICS.emote_cache.inc(&[("cache", "emote_ids"), ("result", "hit")]);
+            return Ok(emote.clone());
+        }
+
+        budget.spend()?;
+        METRICS.emote_cache.inc(&[("cache", "emote_ids"), ("result", "miss")]);
+        let emote = self.seventv_client.get_emote_by_id(emote_id).await?;
+        self.emote_id_map.insert(emote_id.to_owned(), emote.clone());
+
+        Ok(emote)
+    }
+
     async fn handle_message(&mut self, msg: EmoteManagerMessage) {
         match msg {
             EmoteManagerMessage::GetUserEmoteByKeyword {
                 sender_cb,
                 twitch_id,
                 emote_keyword,
+                budget,
             } => {
-                let emote = self.get_user_emote(&twitch_id, &emote_keyword).await;
-                sender_cb.send(emote).expect("Should send response");
+                let emote = self.get_user_emote(&twitch_id, &emote_keyword, &budget).await;
+                // The requester may have timed out, whatever was loaded stays cached either way
+                let _ = sender_cb.send(emote);
             },
             EmoteManagerMessage::GetPopularEmoteByKeyword {
                 sender_cb,
                 emote_keyword,
+                budget,
             } => {
-                let emote = self.get_emote(&emote_keyword).await;
-                sender_cb.send(emote).expect("Should send response");
+                let emote = self.get_emote(&emote_keyword, &budget).await;
+                let _ = sender_cb.send(emote);
+            },
+            EmoteManagerMessage::GetEmoteById {
+                sender_cb,
+                emote_id,
+                budget,
+            } => {
+                let emote = self.get_emote_by_id(&emote_id, &budget).await;
+                let _ = sender_cb.send(emote);
+            },
+            EmoteManagerMessage::Ping { sender_cb } => {
+                let _ = sender_cb.send(());
             },
         }
     }
@@ -154,28 +217,32 @@ async fn run_emote_manager(mut em: EmoteManager) {
 #[derive(Clone)]
 pub struct EmoteManagerHandle {
     sender: mpsc::Sender<EmoteManagerMessage>,
+    // Shared by the clones, for telling whether the actor is still alive
+    task: Arc<JoinHandle<()>>,
 }
 
 impl EmoteManagerHandle {
-    pub fn new() -> Self {
-        let (tx, rx) = mpsc::channel(50);
-        let actor = EmoteManager::new(rx);
-        tokio::spawn(run_emote_manager(actor));
+    pub fn new(config: &Config, seventv_client: Arc<SevenTvClient>) -> Self {
+        let (tx, rx) = mpsc::channel(config.actor_channel_capacity);
+        let actor = EmoteManager::new(rx, seventv_client, config.user_emote_reload_cooldown);
+        let task = Arc::new(tokio::spawn(run_emote_manager(actor)));
 
-        Self { sender: tx }
+        Self { sender: tx, task }
     }
 
     pub async fn get_user_emote(
         &self,
         twitch_id: &str,
         emote_keyword: &str,
-    ) -> Result<SevenUserEmote, String> {
+        budget: &UpstreamBudget,
+    ) -> Result<SevenUserEmote, Error> {
         let (tx, rx) = oneshot::channel();
 
         let msg = EmoteManagerMessage::GetUserEmoteByKeyword {
             sender_cb: tx,
             twitch_id: twitch_id.to_owned(),
             emote_keyword: emote_keyword.to_owned(),
+            budget: budget.clone(),
         };
 
         let _ = self.sender.send(msg).await;
@@ -185,15 +252,54 @@ impl EmoteManagerHandle {
     pub async fn get_popular_emote(
         &self,
         emote_keyword: &str,
-    ) -> Result<SevenUserEmote, String> {
+        budget: &UpstreamBudget,
+    ) -> Result<SevenUserEmote, Error> {
         let (tx, rx) = oneshot::channel();
 
         let msg = EmoteManagerMessage::GetPopularEmoteByKeyword {
             sender_cb: tx,
             emote_keyword: emote_keyword.to_owned(),
+            budget: budget.clone(),
         };
 
         let _ = self.sender.send(msg).await;
         rx.await.expect("Task has been killed")
     }
+
+    pub async fn get_emote_by_id(
+        &self,
+        emote_id: &str,
+        budget: &UpstreamBudget,
+    ) -> Result<SevenUserEmote, Error> {
+        let (tx, rx) = oneshot::channel();
+
+        let msg = EmoteManagerMessage::GetEmoteById {
+            sender_cb: tx,
+            emote_id: emote_id.to_owned(),
+            budget: budget.clone(),
+        };
+
+        let _ = self.sender.send(msg).await;
+        rx.await.expect("Task has been killed")
+    }
+
+    // Whether the actor task is still there, however busy it is
+    pub fn is_running(&self) -> bool {
+        ! self.task.is_finished() && ! self.sender.is_closed()
+    }
+
+    // Whether the actor gets through what's queued before it, which can take a while when it's busy
+    pub async fn ping(&self) -> bool {
+        let (tx, rx) = oneshot::channel();
+
+        if self.sender.send(EmoteManagerMessage::Ping { sender_cb: tx }).await.is_err() {
+            return false;
+        }
+
+        rx.await.is_ok()
+    }
+
+    pub fn mailbox_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
 }
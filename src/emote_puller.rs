@@ -1,178 +1,610 @@
-use std::{collections::HashMap, sync::Arc, unreachable, path::{PathBuf, Path}, unimplemented};
-use std::str;
+use std::{collections::{HashMap, HashSet}, future::Future, sync::Arc, path::{PathBuf, Path}, time::Instant};
 
-use tokio::{sync::{Semaphore, oneshot, mpsc}, process::Command, fs};
+use tokio::{sync::{Semaphore, oneshot, mpsc}, process::Command, fs, task::JoinHandle};
+use tracing::debug;
 
-use crate::seventv::{download_emote, SevenUserEmote};
+use crate::{seventv::{SevenTvClient, SevenUserEmote}, error::Error, compose::{self, Composition}, config::{Config, EmoteSize, OutputFormat}, metrics::METRICS, rate_limit::UpstreamBudget};
+
+// Every resized variant is a file of its own, so one emote only gets so many sizes
+const MAX_RESIZED_VARIANTS: usize = 64;
+
+type Reply = oneshot::Sender<Result<PathBuf, Error>>;
 
 enum EmoteStatus {
-    Pending(Arc<Semaphore>), // downloading/converting
+    // Worked on in its own task, everyone asking in the meantime gets the same result
+    Pending(Vec<Reply>),
     Ready,
 }
 
 
+// Pixels, only one side given keeps the aspect ratio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl Resize {
+    // Like 48x, x112 or 48x112
+    fn dimensions(&self) -> String {
+        let side = |x: Option<u32>| x.map(|x| x.to_string()).unwrap_or_default();
+        format!("{}x{}", side(self.width), side(self.height))
+    }
+
+    // ImageMagick geometry, with both sides given that's exactly the size asked for
+    pub fn geometry(&self) -> String {
+        match self.width.is_some() && self.height.is_some() {
+            true => format!("{}!", self.dimensions()),
+            false => self.dimensions(),
+        }
+    }
+}
+
+// Which file of an emote is wanted, every variant is cached on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Variant {
+    pub size: EmoteSize,
+    pub resize: Option<Resize>,
+    // Settled for the emote already, see OutputFormat::for_emote
+    pub format: OutputFormat,
+}
+
+impl Variant {
+    // What the other variants are converted from, the file as 7tv has it
+    pub fn source(&self) -> Variant {
+        Variant { resize: None, format: OutputFormat::Webp, ..*self }
+    }
+
+    // Goes into filenames and cache keys, like "@2x.gif" or "@2x-48x.webp"
+    fn suffix(&self) -> String {
+        let extension = self.format.extension();
+
+        match self.resize {
+            Some(resize) => format!("@{}-{}.{extension}", self.size, resize.dimensions()),
+            None => format!("@{}.{extension}", self.size),
+        }
+    }
+}
+
 enum EmotePullerMessage {
     PullEmote {
         emote: SevenUserEmote,
-        sender_cb: oneshot::Sender<Result<(), String>>,
+        variant: Variant,
+        budget: UpstreamBudget,
+        sender_cb: oneshot::Sender<Result<PathBuf, Error>>,
+    },
+    // Emotes have to be pulled already
+    ComposeEmotes {
+        emotes: Vec<(SevenUserEmote, PathBuf)>,
+        composition: Composition,
+        variant: Variant,
+        sender_cb: oneshot::Sender<Result<PathBuf, Error>>,
+    },
+    // From the task working on `key`, once the file is in place or it failed
+    Finished {
+        key: String,
+        result: Result<PathBuf, Error>,
+    },
+    // Answered once the actor gets to it, work running in its own task doesn't hold it up
+    Ping {
+        sender_cb: oneshot::Sender<()>,
+    },
+    // Answered once everything started before it is done, the actor stops afterwards
+    Shutdown {
+        sender_cb: oneshot::Sender<()>,
+    },
+}
+
+// "abc@2x-48x.gif" is a resized variant of abc
+fn resized_emote_id(filename: &str) -> Option<&str> {
+    let (id, suffix) = filename.split_once('@')?;
+    (! filename.starts_with('.') && suffix.contains('-')).then_some(id)
+}
+
+// Asks the puller for a file and waits for it to be there
+async fn request_pull(
+    puller: &mpsc::Sender<EmotePullerMessage>,
+    emote: SevenUserEmote,
+    variant: Variant,
+    budget: &UpstreamBudget,
+) -> Result<PathBuf, Error> {
+    let (tx, rx) = oneshot::channel();
+
+    let msg = EmotePullerMessage::PullEmote {
+        sender_cb: tx,
+        emote,
+        variant,
+        budget: budget.clone(),
+    };
+
+    let _ = puller.send(msg).await;
+    rx.await.expect("Task has been killed")
+}
+
+// What the tasks doing the actual work need, cloned into each of them
+#[derive(Clone)]
+struct Worker {
+    seventv_client: Arc<SevenTvClient>,
+    cache_dir: PathBuf,
+    // Every convert run holds a permit, so a burst of conversions can't take the machine down
+    conversions: Arc<Semaphore>,
+    // The puller itself, to report back to and to ask for the files others are made from
+    puller: mpsc::Sender<EmotePullerMessage>,
+}
+
+impl Worker {
+    // Also the key in the emote map, so every variant is tracked on its own
+    fn get_emote_filename(&self, emote: &SevenUserEmote, variant: &Variant) -> String {
+        format!("{}{}", emote.id, variant.suffix())
+    }
+
+    fn get_pulled_emote_path(&self, emote: &SevenUserEmote, variant: &Variant) -> PathBuf {
+        let mut path = self.cache_dir.clone();
+        path.push(self.get_emote_filename(emote, variant));
+        path
+    }
+
+    // Work files live next to the cache so the final rename never crosses filesystems
+    fn get_temp_path(&self, emote: &SevenUserEmote, variant: &Variant, extension: &str) -> PathBuf {
+        let mut path = self.cache_dir.clone();
+        path.push(format!(".{}.tmp.{extension}", self.get_emote_filename(emote, variant)));
+        path
+    }
+
+    // Falls back to the closest size 7tv has when it doesn't have the one asked for
+    async fn download_emote(
+        &self,
+        emote: &SevenUserEmote,
+        variant: &Variant,
+        budget: &UpstreamBudget,
+        temp_path: &Path,
+    ) -> Result<(), Error> {
+        let mut result = Err(Error::NotFound("Emote not found".to_owned()));
+
+        for size in variant.size.by_closeness() {
+            budget.spend()?;
+
+            result = self.seventv_client.download_emote(&emote.id, size, temp_path).await;
+
+            match &result {
+                Err(Error::NotFound(_)) => debug!(id = emote.id, %size, "Emote size not available"),
+                _ => break,
+            }
+        }
+
+        result
+    }
+
+    // Resizing goes frame by frame, animated webp frames only cover what changed until they're coalesced
+    async fn convert(&self, emote: &SevenUserEmote, variant: &Variant, from: &Path, to: &Path) -> Result<(), Error> {
+        let _permit = self.conversions.acquire()
+            .await
+            .expect("Conversion semaphore is never closed");
+        let started = Instant::now();
+
+        let mut command = Command::new("convert");
+        command
+            .arg("-dispose")
+            .arg("Background")
+            .arg(from.as_os_str());
+
+        if let Some(resize) = variant.resize {
+            command
+                .arg("-coalesce")
+                .arg("-resize")
+                .arg(resize.geometry());
+        }
+
+        let output = command
+            .arg(compose::output_path(to))
+            .output()
+            .await
+            .map_err(|x| Error::io(format!("Failed to run convert for emote {}", emote.id), x))?;
+
+        METRICS.emote_conversion_duration.observe(&[], started.elapsed().as_secs_f64());
+
+        if ! output.status.success() {
+            METRICS.emote_conversion_failures.inc(&[]);
+            let _ = fs::remove_file(to).await;
+
+            return Err(Error::Conversion(format!(
+                "Emote {}: {}",
+                emote.id,
+                String::from_utf8_lossy(&output.stderr),
+            )));
+        }
+
+        debug!(id = emote.id, resize = ?variant.resize, to = variant.format.extension(), "Converted emote");
+
+        Ok(())
+    }
+
+    // Only ever the source variant, 7tv's files are webp already
+    async fn pull(&self, emote: &SevenUserEmote, variant: &Variant, budget: &UpstreamBudget) -> Result<PathBuf, Error> {
+        let pulled_path = self.get_pulled_emote_path(emote, variant);
+
+        // Pulled by an earlier run
+        if fs::metadata(&pulled_path).await.is_ok() {
+            return Ok(pulled_path);
+        }
+
+        let temp_path = self.get_temp_path(emote, variant, "webp");
+        let pulled = async {
+            self.download_emote(emote, variant, budget, &temp_path).await?;
+            fs::rename(&temp_path, &pulled_path)
+                .await
+                .map_err(|x| Error::io(format!("Failed to move emote {}", emote.id), x))
+        }.await;
+
+        METRICS.emote_downloads.inc(&[("result", if pulled.is_ok() { "ok" } else { "error" })]);
+
+        if let Err(err) = pulled {
+            let _ = fs::remove_file(&temp_path).await;
+
+            return Err(err);
+        }
+
+        debug!(id = emote.id, name = emote.name, "Pulled emote");
+
+        Ok(pulled_path)
+    }
+
+    // Made from the source variant, so another format or size costs no download
+    async fn derive(&self, emote: &SevenUserEmote, variant: &Variant, budget: &UpstreamBudget) -> Result<PathBuf, Error> {
+        let derived_path = self.get_pulled_emote_path(emote, variant);
+
+        if fs::metadata(&derived_path).await.is_ok() {
+            return Ok(derived_path);
+        }
+
+        let source_path = request_pull(&self.puller, emote.clone(), variant.source(), budget).await?;
+        let temp_path = self.get_temp_path(emote, variant, &format!("out.{}", variant.format.extension()));
+
+        self.convert(emote, variant, &source_path, &temp_path).await?;
+        fs::rename(&temp_path, &derived_path)
+            .await
+            .map_err(|x| Error::io(format!("Failed to move emote {}", emote.id), x))?;
+
+        Ok(derived_path)
+    }
+
+    async fn compose(
+        &self,
+        key: &str,
+        inputs: &[PathBuf],
+        composition: &Composition,
+        variant: &Variant,
+    ) -> Result<PathBuf, Error> {
+        let composed_path = self.cache_dir.join(key);
+
+        if fs::metadata(&composed_path).await.is_ok() {
+            return Ok(composed_path);
+        }
+
+        let work_dir = self.cache_dir.join(format!(".{key}.tmp"));
+        let temp_path = self.cache_dir.join(format!(".{key}.tmp.{}", variant.format.extension()));
+
+        let _permit = self.conversions.acquire()
+            .await
+            .expect("Conversion semaphore is never closed");
+        let started = Instant::now();
+
+        let composed = async {
+            fs::create_dir_all(&work_dir)
+                .await
+                .map_err(|x| Error::io(format!("Failed to create work directory for {key}"), x))?;
+            composition.render(inputs, variant.resize.map(|x| x.geometry()), &work_dir, &temp_path).await?;
+            fs::rename(&temp_path, &composed_path)
+                .await
+                .map_err(|x| Error::io(format!("Failed to move composed emote {key}"), x))
+        }.await;
+
+        let _ = fs::remove_dir_all(&work_dir).await;
+        METRICS.emote_conversion_duration.observe(&[], started.elapsed().as_secs_f64());
+
+        if let Err(err) = composed {
+            METRICS.emote_conversion_failures.inc(&[]);
+            let _ = fs::remove_file(&temp_path).await;
+
+            return Err(err);
+        }
+
+        debug!(key, ?composition, "Composed emotes");
+
+        Ok(composed_path)
     }
 }
 
+// Only keeps track of what's cached and what's being worked on, downloads,
+// conversions and compositions run in tasks of their own
 pub struct EmotePuller {
     emote_map: HashMap<String, EmoteStatus>,
     receiver: mpsc::Receiver<EmotePullerMessage>,
+    worker: Worker,
+    // Keys of every resized variant of an emote, cached or being made
+    resized: HashMap<String, HashSet<String>>,
+    // Tasks that haven't reported back yet
+    running: usize,
+    // Answered once the last task reports back
+    shutdown: Option<oneshot::Sender<()>>,
 }
 
 impl EmotePuller {
-    fn new(receiver: mpsc::Receiver<EmotePullerMessage>) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<EmotePullerMessage>,
+        sender: mpsc::Sender<EmotePullerMessage>,
+        seventv_client: Arc<SevenTvClient>,
+        config: &Config,
+    ) -> Self {
         Self {
             receiver,
             emote_map: HashMap::new(),
+            worker: Worker {
+                seventv_client,
+                cache_dir: config.cache_dir.clone(),
+                conversions: Arc::new(Semaphore::new(config.max_conversions)),
+                puller: sender,
+            },
+            resized: HashMap::new(),
+            running: 0,
+            shutdown: None,
         }
     }
 
-    fn get_emote_filename(emote: &SevenUserEmote) -> String {
-        let id = emote.id.as_str();
-        if emote.animated {
-            format!("{id}.gif")
-        } else {
-            format!("{id}.webp")
+    // Answers right away when the file is ready, or once the task working on it is done.
+    // The reply is handed back when nobody is working on it yet
+    fn wait_for(&mut self, key: &str, path: &Path, sender_cb: Reply) -> Option<Reply> {
+        match self.emote_map.get_mut(key) {
+            Some(EmoteStatus::Ready) => {
+                let _ = sender_cb.send(Ok(path.to_path_buf()));
+                None
+            },
+            Some(EmoteStatus::Pending(waiting)) => {
+                waiting.push(sender_cb);
+                None
+            },
+            None => Some(sender_cb),
         }
     }
 
-    pub fn get_pulled_emote_path(emote: &SevenUserEmote) -> String {
-        let mut path = PathBuf::from("./emotes");
-        path.push(Self::get_emote_filename(emote));
-        path.to_string_lossy().to_string()
+    // Runs the work for `key` in its own task, the actor only hears back once it's done
+    fn spawn_work(
+        &mut self,
+        key: String,
+        sender_cb: Reply,
+        work: impl Future<Output = Result<PathBuf, Error>> + Send + 'static,
+    ) {
+        let sender = self.worker.puller.clone();
+        self.emote_map.insert(key.clone(), EmoteStatus::Pending(vec![sender_cb]));
+        self.running += 1;
+
+        tokio::spawn(async move {
+            // A panic has to be reported too, whoever waits for the result would wait forever otherwise
+            let result = tokio::spawn(work)
+                .await
+                .unwrap_or_else(|x| Err(Error::Conversion(format!("{key}: {x}"))));
+
+            let _ = sender.send(EmotePullerMessage::Finished { key, result }).await;
+        });
     }
 
-    async fn emote_file_exists(emote: &SevenUserEmote) -> bool {
-        fs::metadata(Self::get_pulled_emote_path(emote))
-            .await
-            .is_ok()
-    }
-
-    async fn process_emote(emote: &SevenUserEmote, temp_path: &Path) -> Result<(), String> {
-        println!("Processing emote: {}", emote.name);
-
-        let from = {
-            if emote.animated {
-                println!("Converting animated emote {}", emote.name);
-                let mut to = temp_path.parent().expect("Emote file should have parent directory").to_path_buf();
-                to.push(Self::get_emote_filename(emote));
-
-                let output = Command::new("convert")
-                    .arg("-dispose")
-                    .arg("Background")
-                    .arg(temp_path.to_path_buf().into_os_string())
-                    .arg(to.to_path_buf().into_os_string())
-                    .output()
-                    .await
-                    .map_err(|x| x.to_string())?;
-                println!("Done converting animated emote {} {:?} {:?}", emote.name, str::from_utf8(&output.stdout), str::from_utf8(&output.stderr));
-
-                to
-            } else {
-                temp_path.to_path_buf()
-            }
+    fn finish(&mut self, key: String, result: Result<PathBuf, Error>) {
+        self.running -= 1;
+
+        let waiting = match self.emote_map.remove(&key) {
+            Some(EmoteStatus::Pending(waiting)) => waiting,
+            _ => Vec::new(),
         };
 
-        let to = Self::get_pulled_emote_path(emote);
-        
-        println!("Moving emote {} {:?} {:?}", emote.name, from, to);
-        fs::rename(from, to)
-            .await
-            .map_err(|x| x.to_string())?;
-        println!("Done processing emote {}", emote.name);
-        
-        Ok(())
+        // Failures are forgotten so the next request retries
+        match &result {
+            Ok(_) => {
+                self.emote_map.insert(key, EmoteStatus::Ready);
+            },
+            Err(_) => {
+                if let Some(resized) = resized_emote_id(&key).and_then(|x| self.resized.get_mut(x)) {
+                    resized.remove(&key);
+                }
+            },
+        }
+
+        for sender_cb in waiting {
+            // The requester may have gone away, the file is cached either way
+            let _ = sender_cb.send(result.clone());
+        }
     }
 
-    async fn load_emote(&mut self, emote: &SevenUserEmote) -> Result<(), String> {
-        let emote_status = self.emote_map.get(&emote.id);
+    fn pull_emote(&mut self, emote: SevenUserEmote, variant: Variant, budget: UpstreamBudget, sender_cb: Reply) {
+        let key = self.worker.get_emote_filename(&emote, &variant);
+        let pulled_path = self.worker.get_pulled_emote_path(&emote, &variant);
 
-        if let None = emote_status {
-            if Self::emote_file_exists(emote).await {
-                self.emote_map.insert(emote.id.to_owned(), EmoteStatus::Ready);
-                return Ok(());
-            }
+        let sender_cb = match self.wait_for(&key, &pulled_path, sender_cb) {
+            Some(sender_cb) => sender_cb,
+            None => return,
+        };
 
-            let semaphore = Arc::new(Semaphore::new(1));
-            self.emote_map.insert(emote.id.to_owned(), EmoteStatus::Pending(semaphore.clone()));
-            let _ = semaphore.acquire()
-                .await
-                .map_err(|x| x.to_string())?;
+        let worker = self.worker.clone();
 
-            // Actually load emote
+        if variant == variant.source() {
+            self.spawn_work(key, sender_cb, async move { worker.pull(&emote, &variant, &budget).await });
+            return;
+        }
 
-            let temp_path = download_emote(&emote.id).await?;
-            Self::process_emote(emote, &temp_path).await?;
+        if variant.resize.is_some() {
+            let resized = self.resized.entry(emote.id.clone()).or_default();
 
-            semaphore.close();
-            self.emote_map.insert(emote.id.to_owned(), EmoteStatus::Ready);
-            
-            return Ok(());
-        }
+            if ! resized.contains(&key) && resized.len() >= MAX_RESIZED_VARIANTS {
+                let message = format!("{} can't be resized to any more sizes, try one that was asked for before", emote.name);
+                let _ = sender_cb.send(Err(Error::InvalidRequest(message)));
 
-        if let Some(status) = emote_status {
-            if let EmoteStatus::Ready = status {
-                return Ok(());
+                return;
             }
 
-            if let EmoteStatus::Pending(semaphore) = status {
-                match semaphore.acquire().await {
-                    Ok(_) => panic!("Should never be able to acquire semaphore ticket"),
-                    Err(_) => {
-                        return Ok(())
-                    },
-                }
+            resized.insert(key.clone());
+        }
+
+        self.spawn_work(key, sender_cb, async move { worker.derive(&emote, &variant, &budget).await });
+    }
+
+    // Resized variants cached by earlier runs count towards the limit too
+    async fn load_resized(&mut self) {
+        let mut entries = match fs::read_dir(&self.worker.cache_dir).await {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+
+            if let Some(id) = resized_emote_id(&filename) {
+                self.resized.entry(id.to_owned()).or_default().insert(filename);
             }
         }
+    }
 
-        unreachable!();
+    // Compositions are cached like emotes, under a key made from the IDs that went into them
+    fn compose_emotes(
+        &mut self,
+        emotes: Vec<(SevenUserEmote, PathBuf)>,
+        composition: Composition,
+        variant: Variant,
+        sender_cb: Reply,
+    ) {
+        let ids: Vec<&str> = emotes.iter().map(|(emote, _)| emote.id.as_str()).collect();
+        let key = format!("{}{}", composition.key(&ids), variant.suffix());
+        let composed_path = self.worker.cache_dir.join(&key);
+
+        if let Some(sender_cb) = self.wait_for(&key, &composed_path, sender_cb) {
+            let inputs: Vec<PathBuf> = emotes.into_iter().map(|(_, path)| path).collect();
+            let worker = self.worker.clone();
+            let task_key = key.clone();
+
+            self.spawn_work(key, sender_cb, async move {
+                worker.compose(&task_key, &inputs, &composition, &variant).await
+            });
+        }
     }
 
-    async fn handle_message(&mut self, msg: EmotePullerMessage) {
+    // Returns false once the actor should stop
+    fn handle_message(&mut self, msg: EmotePullerMessage) -> bool {
         match msg {
-            EmotePullerMessage::PullEmote { sender_cb, emote } => {
-                let emote = self.load_emote(&emote).await;
-                sender_cb.send(emote).expect("Should send response");
+            EmotePullerMessage::PullEmote { sender_cb, emote, variant, budget } => {
+                self.pull_emote(emote, variant, budget, sender_cb);
+            },
+            EmotePullerMessage::ComposeEmotes { emotes, composition, variant, sender_cb } => {
+                self.compose_emotes(emotes, composition, variant, sender_cb);
+            },
+            EmotePullerMessage::Finished { key, result } => {
+                self.finish(key, result);
+            },
+            EmotePullerMessage::Ping { sender_cb } => {
+                let _ = sender_cb.send(());
+            },
+            EmotePullerMessage::Shutdown { sender_cb } => {
+                self.shutdown = Some(sender_cb);
             },
         }
+
+        // Work still running has to report back before the actor can stop
+        if self.running > 0 {
+            return true;
+        }
+
+        match self.shutdown.take() {
+            Some(sender_cb) => {
+                self.receiver.close();
+                let _ = sender_cb.send(());
+
+                false
+            },
+            None => true,
+        }
     }
 }
 
 async fn run_emote_puller(mut ep: EmotePuller) {
+    ep.load_resized().await;
+
     while let Some(msg) = ep.receiver.recv().await {
-        ep.handle_message(msg).await;
+        if ! ep.handle_message(msg) {
+            break;
+        }
     }
 }
 
 pub struct EmotePullerHandle {
     sender: mpsc::Sender<EmotePullerMessage>,
+    task: JoinHandle<()>,
 }
 
 impl EmotePullerHandle {
-    pub fn new() -> Self {
-        let (tx, rx) = mpsc::channel(50);
-        let actor = EmotePuller::new(rx);
-        tokio::spawn(run_emote_puller(actor));
+    pub fn new(config: &Config, seventv_client: Arc<SevenTvClient>) -> Self {
+        let (tx, rx) = mpsc::channel(config.actor_channel_capacity);
+        let actor = EmotePuller::new(rx, tx.clone(), seventv_client, config);
+        let task = tokio::spawn(run_emote_puller(actor));
 
-        Self { sender: tx }
+        Self { sender: tx, task }
     }
 
+    // Resolves to the path of the pulled file inside the cache directory
     pub async fn pull_emote(
         &self,
         emote: SevenUserEmote,
-    ) -> Result<(), String> {
+        variant: Variant,
+        budget: &UpstreamBudget,
+    ) -> Result<PathBuf, Error> {
+        request_pull(&self.sender, emote, variant, budget).await
+    }
+
+    // Puts pulled emotes together into one image, resolves to the path of the result
+    pub async fn compose_emotes(
+        &self,
+        emotes: Vec<(SevenUserEmote, PathBuf)>,
+        composition: Composition,
+        variant: Variant,
+    ) -> Result<PathBuf, Error> {
         let (tx, rx) = oneshot::channel();
 
-        let msg = EmotePullerMessage::PullEmote {
+        let msg = EmotePullerMessage::ComposeEmotes {
             sender_cb: tx,
-            emote,
+            emotes,
+            composition,
+            variant,
         };
 
         let _ = self.sender.send(msg).await;
         rx.await.expect("Task has been killed")
     }
+
+    // Whether the actor task is still there, however busy it is
+    pub fn is_running(&self) -> bool {
+        ! self.task.is_finished() && ! self.sender.is_closed()
+    }
+
+    // Whether the actor gets through what's queued before it, which can take a while when it's busy
+    pub async fn ping(&self) -> bool {
+        let (tx, rx) = oneshot::channel();
+
+        if self.sender.send(EmotePullerMessage::Ping { sender_cb: tx }).await.is_err() {
+            return false;
+        }
+
+        rx.await.is_ok()
+    }
+
+    pub fn mailbox_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub async fn shutdown(&self) {
+        let (tx, rx) = oneshot::channel();
+
+        let msg = EmotePullerMessage::Shutdown {
+            sender_cb: tx,
+        };
+
+        if self.sender.send(msg).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use std::{fmt, io, sync::Arc};
+
+use crate::http::HttpStatus;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upstream {
+    SevenTv,
+    Twitch,
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::SevenTv => write!(f, "7tv"),
+            Upstream::Twitch => write!(f, "Twitch"),
+        }
+    }
+}
+
+// Clone so everyone waiting for the same emote can get the same failure
+#[derive(Debug, Clone)]
+pub enum Error {
+    // The upstream couldn't be reached or answered with an error status
+    UpstreamHttp {
+        upstream: Upstream,
+        message: String,
+    },
+    // The upstream answered but we couldn't make sense of the body
+    UpstreamDecode {
+        upstream: Upstream,
+        message: String,
+    },
+    // None when the resolution as a whole took too long rather than a single call
+    UpstreamTimeout(Option<Upstream>),
+    NotFound(String),
+    // Carries the value for the Allow header
+    MethodNotAllowed(String),
+    InvalidRequest(String),
+    // The client didn't send a complete request in time
+    RequestTimeout,
+    // Limits in bytes that the request went over
+    HeadTooLarge(usize),
+    BodyTooLarge(usize),
+    // Seconds until the client may try again
+    RateLimited(u64),
+    Overloaded(u64),
+    Conversion(String),
+    // Invalid settings, only ever produced at startup
+    Config(String),
+    Io {
+        context: String,
+        source: Arc<io::Error>,
+    },
+}
+
+impl Error {
+    pub fn upstream_http(upstream: Upstream, err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            return Error::UpstreamTimeout(Some(upstream));
+        }
+
+        Error::UpstreamHttp { upstream, message: err.to_string() }
+    }
+
+    pub fn upstream_decode(upstream: Upstream, err: impl fmt::Display) -> Self {
+        Error::UpstreamDecode { upstream, message: err.to_string() }
+    }
+
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        Error::Io { context: context.into(), source: Arc::new(source) }
+    }
+
+    pub fn status(&self) -> HttpStatus {
+        match self {
+            Error::UpstreamHttp { .. } | Error::UpstreamDecode { .. } => HttpStatus::BadGateway,
+            Error::UpstreamTimeout(_) => HttpStatus::GatewayTimeout,
+            Error::NotFound(_) => HttpStatus::NotFound,
+            Error::MethodNotAllowed(_) => HttpStatus::MethodNotAllowed,
+            Error::InvalidRequest(_) => HttpStatus::BadRequest,
+            Error::RequestTimeout => HttpStatus::RequestTimeout,
+            Error::HeadTooLarge(_) => HttpStatus::RequestHeaderFieldsTooLarge,
+            Error::BodyTooLarge(_) => HttpStatus::PayloadTooLarge,
+            Error::RateLimited(_) => HttpStatus::TooManyRequests,
+            Error::Overloaded(_) => HttpStatus::ServiceUnavailable,
+            Error::Conversion(_) | Error::Config(_) | Error::Io { .. } => HttpStatus::InternalServerError,
+        }
+    }
+
+    // 7tv or Twitch failed us, as opposed to answering that something doesn't exist
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(self, Error::UpstreamHttp { .. } | Error::UpstreamDecode { .. } | Error::UpstreamTimeout(_))
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::RateLimited(seconds) | Error::Overloaded(seconds) => Some(*seconds),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UpstreamHttp { upstream, message } => write!(f, "{upstream}: Request failed: {message}"),
+            Error::UpstreamDecode { upstream, message } => write!(f, "{upstream}: Invalid response: {message}"),
+            Error::UpstreamTimeout(Some(upstream)) => write!(f, "{upstream}: Request timed out"),
+            Error::UpstreamTimeout(None) => write!(f, "Resolving the emote timed out"),
+            Error::NotFound(message) => write!(f, "{message}"),
+            Error::MethodNotAllowed(allow) => write!(f, "Method not allowed, use one of {allow}"),
+            Error::InvalidRequest(message) => write!(f, "{message}"),
+            Error::RequestTimeout => write!(f, "Timed out reading the request"),
+            Error::HeadTooLarge(limit) => write!(f, "Request head is larger than {limit} bytes"),
+            Error::BodyTooLarge(limit) => write!(f, "Request body is larger than {limit} bytes"),
+            Error::RateLimited(seconds) => write!(f, "Too many requests, try again in {seconds}s"),
+            Error::Overloaded(seconds) => write!(f, "Server is overloaded, try again in {seconds}s"),
+            Error::Conversion(message) => write!(f, "Conversion failed: {message}"),
+            Error::Config(message) => write!(f, "Invalid config: {message}"),
+            Error::Io { context, source } => write!(f, "{context}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
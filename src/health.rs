@@ -0,0 +1,128 @@
+use std::{process::Stdio, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::{fs, process::Command, time};
+
+use crate::{http::{HttpResponse, HttpStatus}, AppState};
+
+// Actors that take longer than this to get to a ping are too backed up to take more work
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+const CONVERTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn check(result: Result<Value, String>) -> (bool, Value) {
+    match result {
+        Ok(details) => (true, json!({ "ok": true, "details": details })),
+        Err(err) => (false, json!({ "ok": false, "error": err })),
+    }
+}
+
+fn respond(checks: Vec<(&str, (bool, Value))>) -> HttpResponse {
+    let healthy = checks.iter().all(|(_, (ok, _))| *ok);
+    let status = if healthy { HttpStatus::Ok } else { HttpStatus::ServiceUnavailable };
+
+    let body = json!({
+        "status": if healthy { "ok" } else { "fail" },
+        "checks": checks.into_iter().map(|(name, (_, x))| (name.to_owned(), x)).collect::<serde_json::Map<_, _>>(),
+    });
+
+    let mut response = HttpResponse::new(status).with_body("application/json", body.to_string().into_bytes());
+    response.set_header("Cache-Control", "no-store");
+    response
+}
+
+fn running(name: &str, running: bool) -> Result<Value, String> {
+    match running {
+        true => Ok(json!("running")),
+        false => Err(format!("{name} has stopped")),
+    }
+}
+
+async fn ping(name: &str, ping: impl std::future::Future<Output = bool>) -> Result<Value, String> {
+    match time::timeout(PING_TIMEOUT, ping).await {
+        Ok(true) => Ok(json!("responding")),
+        Ok(false) => Err(format!("{name} has stopped")),
+        Err(_) => Err(format!("{name} didn't answer within {}s", PING_TIMEOUT.as_secs())),
+    }
+}
+
+// The process is up and the actors haven't died. A slow answer only means an actor
+// is backed up, that's for /readyz
+pub async fn healthz(state: &AppState) -> HttpResponse {
+    respond(vec![
+        ("emote_manager", check(running("emote_manager", state.emote_manager.is_running()))),
+        ("emote_puller", check(running("emote_puller", state.emote_puller.is_running()))),
+    ])
+}
+
+async fn cache_dir_writable(state: &AppState) -> Result<Value, String> {
+    let path = state.config.cache_dir.join(".readyz.tmp");
+
+    fs::write(&path, b"ok")
+        .await
+        .map_err(|x| format!("Can't write to {}: {x}", state.config.cache_dir.display()))?;
+    let _ = fs::remove_file(&path).await;
+
+    Ok(json!(state.config.cache_dir.display().to_string()))
+}
+
+// Animated emotes can't be converted without ImageMagick
+async fn converter_available() -> Result<Value, String> {
+    let output = Command::new("convert")
+        .arg("-version")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let output = time::timeout(CONVERTER_TIMEOUT, output)
+        .await
+        .map_err(|_| "convert -version timed out".to_owned())?
+        .map_err(|x| format!("Can't run convert: {x}"))?;
+
+    if ! output.status.success() {
+        return Err(format!("convert -version exited with {}", output.status));
+    }
+
+    let version = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+
+    Ok(json!(version))
+}
+
+async fn twitch_token(state: &AppState) -> Result<Value, String> {
+    state.twitch_client.get_auth_token()
+        .await
+        .map(|_| json!("token available"))
+        .map_err(|x| x.to_string())
+}
+
+// Reported but never failed: once out of rotation no more calls are made, so a failure
+// would never clear. Calls that fail still fail the requests that made them
+fn seventv_status(state: &AppState) -> Result<Value, String> {
+    Ok(match state.seventv_client.last_call() {
+        None => json!("no calls yet"),
+        Some(call) => json!({ "last_call_at": call.at, "last_call_failure": call.failure }),
+    })
+}
+
+// Whether this instance can actually serve emotes right now
+pub async fn readyz(state: &AppState) -> HttpResponse {
+    let (cache_dir, converter, twitch, emote_manager, emote_puller) = tokio::join!(
+        cache_dir_writable(state),
+        converter_available(),
+        twitch_token(state),
+        ping("emote_manager", state.emote_manager.ping()),
+        ping("emote_puller", state.emote_puller.ping()),
+    );
+
+    respond(vec![
+        ("emote_manager", check(emote_manager)),
+        ("emote_puller", check(emote_puller)),
+        ("cache_dir", check(cache_dir)),
+        ("converter", check(converter)),
+        ("twitch", check(twitch)),
+        ("seventv", check(seventv_status(state))),
+    ])
+}
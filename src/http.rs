@@ -1,70 +1,160 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::net::IpAddr;
 use std::str;
+use std::sync::{atomic::AtomicUsize, Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::Error;
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum HttpVerb {
     Get,
+    Head,
+    Options,
     Post,
     Update,
     Put,
     Delete,
 }
 
-#[derive(Debug)]
+impl HttpVerb {
+    pub fn parse(input: &str) -> Option<HttpVerb> {
+        match input.to_ascii_uppercase().as_str() {
+            "GET" => Some(HttpVerb::Get),
+            "HEAD" => Some(HttpVerb::Head),
+            "OPTIONS" => Some(HttpVerb::Options),
+            "POST" => Some(HttpVerb::Post),
+            "UPDATE" => Some(HttpVerb::Update),
+            "PUT" => Some(HttpVerb::Put),
+            "DELETE" => Some(HttpVerb::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVerb::Get => "GET",
+            HttpVerb::Head => "HEAD",
+            HttpVerb::Options => "OPTIONS",
+            HttpVerb::Post => "POST",
+            HttpVerb::Update => "UPDATE",
+            HttpVerb::Put => "PUT",
+            HttpVerb::Delete => "DELETE",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(non_camel_case_types)]
 pub enum HttpVersion {
     v0_9,
     v1_0,
     v1_1,
     v2_0,
-    v3_0,
+}
+
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::v0_9 => "HTTP/0.9",
+            HttpVersion::v1_0 => "HTTP/1.0",
+            HttpVersion::v1_1 => "HTTP/1.1",
+            HttpVersion::v2_0 => "HTTP/2.0",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct HttpRequest {
     pub verb: HttpVerb,
     pub version: HttpVersion,
+    // Raw request target as sent by the client
+    pub target: String,
+    // Percent-decoded path without the query string
     pub pathname: String,
+    pub query: HashMap<String, Vec<String>>,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    // Set by the connection once it's known, see proxy::client_ip
+    pub client_ip: Option<IpAddr>,
+    // Calls to 7tv or Twitch made while handling the request, for the access log
+    pub upstream_calls: Arc<AtomicUsize>,
 }
 
-impl HttpRequest {
-    fn read_head(input: &[u8]) -> Result<&[u8], String> {
-        let mut i = 0;
-        let mut j = 0;
-        let separator: Vec<u8> = "\r\n\r\n"
-            .chars()
-            .map(|x| x as u8)
-            .collect();
-
-        for byte in input {
-            if j == 4 {
-                return Ok(&input[0..i]);
-            }
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
 
-            if *byte == separator[j] {
-                j += 1;
-            } else {
-                j = 0;
-            }
+// Decodes %XX escapes, query strings additionally encode spaces as '+'
+fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
 
-            i += 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let high = hex_value(*bytes.get(i + 1)?)?;
+                let low = hex_value(*bytes.get(i + 2)?)?;
+                decoded.push(high << 4 | low);
+                i += 3;
+            },
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            },
         }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn parse_query(input: &str) -> Option<HashMap<String, Vec<String>>> {
+    let mut query = HashMap::<String, Vec<String>>::new();
+
+    for pair in input.split('&').filter(|x| !x.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
 
-        Err("Couldn't find header separator".to_owned())
+        query
+            .entry(percent_decode(key, true)?)
+            .or_default()
+            .push(percent_decode(value, true)?);
+    }
+
+    Some(query)
+}
+
+const HEAD_SEPARATOR: &[u8] = b"\r\n\r\n";
+
+fn find_head_end(input: &[u8]) -> Option<usize> {
+    input.windows(HEAD_SEPARATOR.len())
+        .position(|window| window == HEAD_SEPARATOR)
+        .map(|i| i + HEAD_SEPARATOR.len())
+}
+
+impl HttpRequest {
+    fn read_head(input: &[u8]) -> Result<&[u8], Error> {
+        find_head_end(input)
+            .map(|end| &input[0..end])
+            .ok_or(Error::InvalidRequest("Couldn't find header separator".to_owned()))
     }
 
     fn parse_request_line(input: &str) -> Option<(HttpVerb, String, HttpVersion)> {
-        let parts: Vec<&str> = input.split(" ").collect();
+        let parts: Vec<&str> = input.split(' ').collect();
 
-        let verb = match parts.get(0)?.to_ascii_uppercase().as_str() {
-            "GET" => HttpVerb::Get,
-            "POST" => HttpVerb::Post,
-            "UPDATE" => HttpVerb::Update,
-            "DELETE" => HttpVerb::Delete,
-            _ => return None,
-        };
+        let verb = HttpVerb::parse(parts.first()?)?;
 
         let pathname = (*parts.get(1)?).to_owned();
         let version = match *parts.get(2)? {
@@ -78,47 +168,717 @@ impl HttpRequest {
         Some((verb, pathname, version))
     }
 
-    fn parse_headers(lines: Vec<&str>) -> HashMap<String, String> {
+    // The body framing headers have to be unambiguous, a proxy in front of us
+    // could have picked the other copy
+    const SINGLE_HEADERS: [&str; 2] = ["content-length", "transfer-encoding"];
+
+    fn parse_headers(lines: Vec<&str>) -> Result<HashMap<String, String>, Error> {
         let mut header_map = HashMap::<String, String>::new();
 
-        lines.iter()
-            .map(|x| x.trim())
-            .filter(|x| x.len() > 0)
-            .for_each(|x| {
-                let header_parts = x.split_once(":");
-                
-                if let Some((key, value)) = header_parts {
-                    header_map.insert(
-                        key.trim().to_owned(),
-                        value.trim().to_owned(),
-                    );
+        for line in lines.iter().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            if let Some((key, value)) = line.split_once(':') {
+                let key = key.trim().to_ascii_lowercase();
+
+                if Self::SINGLE_HEADERS.contains(&key.as_str()) && header_map.contains_key(&key) {
+                    return Err(Error::InvalidRequest(format!("Repeated {key} header")));
                 }
-            });
 
-        header_map
+                header_map.insert(key, value.trim().to_owned());
+            }
+        }
+
+        Ok(header_map)
     }
 
-    pub fn parse(input: &[u8]) -> Result<HttpRequest, String> {
+    pub fn parse(input: &[u8]) -> Result<HttpRequest, Error> {
+        let invalid_head = || Error::InvalidRequest("Failed to parse http head".to_owned());
+
         let head_bin = Self::read_head(input)?;
         let mut head = str::from_utf8(head_bin)
-            .map_err(|_| "Failed to parse http head".to_owned())?
+            .map_err(|_| invalid_head())?
             .split("\r\n");
 
-        let request_line = head.next().ok_or("Failed to parse http head".to_owned())?;
-        let (verb, pathname, version) = Self::parse_request_line(request_line)
-            .ok_or("Failed to parse http head".to_owned())?;
+        let request_line = head.next().ok_or_else(invalid_head)?;
+        let (verb, target, version) = Self::parse_request_line(request_line)
+            .ok_or_else(invalid_head)?;
+
         let header_part = head.collect::<Vec<&str>>();
 
-        let headers_map = Self::parse_headers(header_part);
+        let headers_map = Self::parse_headers(header_part)?;
 
         let body = input[head_bin.len()..].to_owned();
-        
+
+        Self::from_parts(verb, version, target, headers_map, body)
+    }
+
+    // Shared by both protocol versions, `headers` keys have to be lowercase already
+    pub fn from_parts(
+        verb: HttpVerb,
+        version: HttpVersion,
+        target: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    ) -> Result<HttpRequest, Error> {
+        let invalid_target = || Error::InvalidRequest("Invalid request target".to_owned());
+        let (raw_path, raw_query) = target.split_once('?').unwrap_or((&target, ""));
+        let pathname = percent_decode(raw_path, false).ok_or_else(invalid_target)?;
+        let query = parse_query(raw_query).ok_or_else(invalid_target)?;
+
         Ok(Self {
             verb,
             version,
+            target,
             pathname,
-            headers: headers_map,
+            query,
+            headers,
             body,
+            client_ip: None,
+            upstream_calls: Arc::default(),
         })
     }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|x| x.as_str())
+    }
+
+    // The first value of a query parameter
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .get(name)
+            .and_then(|x| x.first())
+            .map(|x| x.as_str())
+    }
+
+    // How much the client wants `mime`, as (q, how specific the range that matched is).
+    // The most specific range decides, without an Accept header anything goes
+    pub fn accepts(&self, mime: &str) -> (f32, u8) {
+        let accept = match self.header("Accept") {
+            Some(accept) => accept,
+            None => return (1.0, 0),
+        };
+
+        let main_type = mime.split('/').next().unwrap_or_default();
+        let mut best: Option<(f32, u8)> = None;
+
+        for range in accept.split(',') {
+            let mut parts = range.split(';').map(|x| x.trim());
+            let media_range = parts.next().unwrap_or_default().to_ascii_lowercase();
+
+            let specificity = match media_range.split_once('/') {
+                _ if media_range == mime => 2,
+                Some((range_type, "*")) if range_type == main_type => 1,
+                Some(("*", "*")) => 0,
+                _ => continue,
+            };
+
+            let q = parts
+                .filter_map(|x| x.strip_prefix("q="))
+                .find_map(|x| x.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if best.map(|(_, x)| specificity > x).unwrap_or(true) {
+                best = Some((q, specificity));
+            }
+        }
+
+        best.unwrap_or((0.0, 0))
+    }
+
+    // Whether the client's cached copy is still valid, If-None-Match takes
+    // precedence over If-Modified-Since when both are sent
+    pub fn is_not_modified(&self, etag: &str, last_modified: Option<SystemTime>) -> bool {
+        if let Some(if_none_match) = self.header("If-None-Match") {
+            return if_none_match
+                .split(',')
+                .map(|x| x.trim())
+                .any(|x| x == "*" || x.trim_start_matches("W/") == etag);
+        }
+
+        let since = self.header("If-Modified-Since")
+            .and_then(|x| httpdate::parse_http_date(x).ok());
+
+        match (since, last_modified) {
+            (Some(since), Some(modified)) => {
+                // HTTP dates only have second precision
+                let secs = |x: SystemTime| x
+                    .duration_since(UNIX_EPOCH)
+                    .map(|x| x.as_secs())
+                    .unwrap_or(0);
+
+                secs(modified) <= secs(since)
+            },
+            _ => false,
+        }
+    }
+
+    // Range requests only apply when the client's copy is the one we'd serve,
+    // If-Range requires a strong validator so weak ETags never match
+    pub fn if_range_matches(&self, etag: &str, last_modified: Option<SystemTime>) -> bool {
+        let if_range = match self.header("If-Range") {
+            Some(if_range) => if_range.trim(),
+            None => return true,
+        };
+
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            return if_range == etag;
+        }
+
+        match (httpdate::parse_http_date(if_range).ok(), last_modified) {
+            (Some(date), Some(modified)) => httpdate::fmt_http_date(date) == httpdate::fmt_http_date(modified),
+            _ => false,
+        }
+    }
+
+    // HTTP/1.1 connections are persistent unless the client opts out,
+    // HTTP/1.0 ones only when the client explicitly asks for it
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let has_token = |token: &str| connection
+            .split(',')
+            .any(|x| x.trim().eq_ignore_ascii_case(token));
+
+        if has_token("close") {
+            return false;
+        }
+
+        self.version >= HttpVersion::v1_1 || has_token("keep-alive")
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    pub max_head_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_head_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+// Reads requests off a connection one after the other, keeping whatever
+// was received past the current request around for the next one
+pub struct RequestReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    limits: RequestLimits,
+}
+
+impl<R: AsyncRead + Unpin> RequestReader<R> {
+    pub fn new(reader: R, limits: RequestLimits) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            limits,
+        }
+    }
+
+    // Reads more data into the buffer, returns false if the peer closed the connection
+    async fn fill_buffer(&mut self) -> Result<bool, Error> {
+        let mut chunk = [0; 4096];
+        let read = self.reader.read(&mut chunk)
+            .await
+            .map_err(|x| Error::io("Failed to read request data", x))?;
+
+        self.buffer.extend_from_slice(&chunk[..read]);
+
+        Ok(read > 0)
+    }
+
+    async fn fill_at_least(&mut self, size: usize) -> Result<(), Error> {
+        while self.buffer.len() < size {
+            if ! self.fill_buffer().await? {
+                return Err(Error::InvalidRequest("Connection closed mid-request".to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(i) = self.buffer.windows(2).position(|x| x == b"\r\n") {
+                let line = self.buffer[..i].to_vec();
+                self.buffer.drain(..i + 2);
+
+                return Ok(line);
+            }
+
+            if self.buffer.len() > self.limits.max_head_size {
+                return Err(Error::HeadTooLarge(self.limits.max_head_size));
+            }
+
+            self.fill_at_least(self.buffer.len() + 1).await?;
+        }
+    }
+
+    fn body_framing(&self, request: &HttpRequest) -> Result<BodyFraming, Error> {
+        let transfer_encoding = request.header("Transfer-Encoding");
+        let content_length = request.header("Content-Length");
+
+        if let Some(transfer_encoding) = transfer_encoding {
+            // Both headers at once is a classic request smuggling vector
+            if content_length.is_some() || ! transfer_encoding.eq_ignore_ascii_case("chunked") {
+                return Err(Error::InvalidRequest("Unsupported Transfer-Encoding".to_owned()));
+            }
+
+            return Ok(BodyFraming::Chunked);
+        }
+
+        match content_length {
+            None => Ok(BodyFraming::Empty),
+            Some(content_length) => {
+                let invalid_length = || Error::InvalidRequest("Invalid Content-Length".to_owned());
+
+                // usize's parser would take a leading '+' too
+                if ! content_length.bytes().all(|x| x.is_ascii_digit()) {
+                    return Err(invalid_length());
+                }
+
+                let length = content_length
+                    .parse::<usize>()
+                    .map_err(|_| invalid_length())?;
+
+                if length > self.limits.max_body_size {
+                    return Err(Error::BodyTooLarge(self.limits.max_body_size));
+                }
+
+                Ok(BodyFraming::Length(length))
+            },
+        }
+    }
+
+    async fn read_chunked_body(&mut self) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+
+        loop {
+            let line = self.read_line().await?;
+            let size = str::from_utf8(&line)
+                .ok()
+                .map(|x| x.split(';').next().unwrap_or("").trim())
+                .and_then(|x| usize::from_str_radix(x, 16).ok())
+                .ok_or(Error::InvalidRequest("Invalid chunk size".to_owned()))?;
+
+            if size == 0 {
+                break;
+            }
+
+            // Chunk sizes come from the client, compare without adding so huge ones can't overflow
+            if size > self.limits.max_body_size.saturating_sub(body.len()) {
+                return Err(Error::BodyTooLarge(self.limits.max_body_size));
+            }
+
+            self.fill_at_least(size + 2).await?;
+
+            if &self.buffer[size..size + 2] != b"\r\n" {
+                return Err(Error::InvalidRequest("Invalid chunk terminator".to_owned()));
+            }
+
+            body.extend(self.buffer.drain(..size));
+            self.buffer.drain(..2);
+        }
+
+        // Trailers aren't used for anything, skip until the terminating empty line
+        while ! self.read_line().await?.is_empty() {}
+
+        Ok(body)
+    }
+
+    // Waits for the first byte of the next request, false if the client closed the connection
+    // instead. Separate from next_request so idling and sending a request can be timed differently
+    pub async fn wait_for_request(&mut self) -> Result<bool, Error> {
+        if ! self.buffer.is_empty() {
+            return Ok(true);
+        }
+
+        self.fill_buffer().await
+    }
+
+    // Ok(None) means the client closed the connection between requests
+    pub async fn next_request(&mut self) -> Result<Option<HttpRequest>, Error> {
+        let end = loop {
+            if let Some(end) = find_head_end(&self.buffer) {
+                break end;
+            }
+
+            if self.buffer.len() > self.limits.max_head_size {
+                return Err(Error::HeadTooLarge(self.limits.max_head_size));
+            }
+
+            if ! self.fill_buffer().await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+
+                return Err(Error::InvalidRequest("Connection closed mid-request".to_owned()));
+            }
+        };
+
+        if end > self.limits.max_head_size {
+            return Err(Error::HeadTooLarge(self.limits.max_head_size));
+        }
+
+        let mut request = HttpRequest::parse(&self.buffer[..end])?;
+        self.buffer.drain(..end);
+
+        request.body = match self.body_framing(&request)? {
+            BodyFraming::Empty => Vec::new(),
+            BodyFraming::Length(length) => {
+                self.fill_at_least(length).await?;
+                self.buffer.drain(..length).collect()
+            },
+            BodyFraming::Chunked => self.read_chunked_body().await?,
+        };
+
+        Ok(Some(request))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpStatus {
+    Ok,
+    NoContent,
+    PartialContent,
+    NotModified,
+    PermanentRedirect,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+}
+
+impl HttpStatus {
+    pub fn code(&self) -> u16 {
+        match self {
+            HttpStatus::Ok => 200,
+            HttpStatus::NoContent => 204,
+            HttpStatus::PartialContent => 206,
+            HttpStatus::NotModified => 304,
+            HttpStatus::PermanentRedirect => 308,
+            HttpStatus::BadRequest => 400,
+            HttpStatus::NotFound => 404,
+            HttpStatus::MethodNotAllowed => 405,
+            HttpStatus::RequestTimeout => 408,
+            HttpStatus::PayloadTooLarge => 413,
+            HttpStatus::RangeNotSatisfiable => 416,
+            HttpStatus::TooManyRequests => 429,
+            HttpStatus::RequestHeaderFieldsTooLarge => 431,
+            HttpStatus::InternalServerError => 500,
+            HttpStatus::BadGateway => 502,
+            HttpStatus::ServiceUnavailable => 503,
+            HttpStatus::GatewayTimeout => 504,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HttpStatus::Ok => "OK",
+            HttpStatus::NoContent => "No Content",
+            HttpStatus::PartialContent => "Partial Content",
+            HttpStatus::NotModified => "Not Modified",
+            HttpStatus::PermanentRedirect => "Permanent Redirect",
+            HttpStatus::BadRequest => "Bad Request",
+            HttpStatus::NotFound => "Not Found",
+            HttpStatus::MethodNotAllowed => "Method Not Allowed",
+            HttpStatus::RequestTimeout => "Request Timeout",
+            HttpStatus::PayloadTooLarge => "Payload Too Large",
+            HttpStatus::RangeNotSatisfiable => "Range Not Satisfiable",
+            HttpStatus::TooManyRequests => "Too Many Requests",
+            HttpStatus::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpStatus::InternalServerError => "Internal Server Error",
+            HttpStatus::BadGateway => "Bad Gateway",
+            HttpStatus::ServiceUnavailable => "Service Unavailable",
+            HttpStatus::GatewayTimeout => "Gateway Timeout",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: HttpStatus,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: HttpStatus) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn ok(content_type: &str, body: Vec<u8>) -> Self {
+        Self::new(HttpStatus::Ok).with_body(content_type, body)
+    }
+
+    // Small JSON body so clients can tell failures apart without parsing text
+    pub fn error(status: HttpStatus, message: &str) -> Self {
+        let body = json!({
+            "status": status.code(),
+            "error": status.reason(),
+            "message": message,
+        });
+
+        Self::new(status).with_body("application/json", body.to_string().into_bytes())
+    }
+
+    // Server side failures can carry upstream URLs, file paths or ImageMagick output, those
+    // only go to the log. The X-Request-Id ties the generic answer to the log line
+    pub fn from_error(err: &Error) -> Self {
+        let status = err.status();
+        let message = match status.code() >= 500 {
+            true => status.reason().to_owned(),
+            false => err.to_string(),
+        };
+
+        let mut response = Self::error(status, &message);
+
+        if let Error::MethodNotAllowed(allow) = err {
+            response.set_header("Allow", allow);
+        }
+
+        if let Some(retry_after) = err.retry_after() {
+            response.set_header("Retry-After", &retry_after.to_string());
+        }
+
+        response
+    }
+
+    pub fn with_body(mut self, content_type: &str, body: Vec<u8>) -> Self {
+        self.set_header("Content-Type", content_type);
+        self.body = body;
+        self
+    }
+
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self.headers.push((key.to_owned(), value.to_owned()));
+    }
+
+    // Vary lists every request header the response depends on, so it's added to instead of replaced
+    pub fn add_vary(&mut self, field: &str) {
+        match self.headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case("Vary")) {
+            Some((_, value)) => {
+                if ! value.split(',').any(|x| x.trim().eq_ignore_ascii_case(field)) {
+                    value.push_str(", ");
+                    value.push_str(field);
+                }
+            },
+            None => self.headers.push(("Vary".to_owned(), field.to_owned())),
+        }
+    }
+
+    // These never have a body, a Content-Length would describe the full representation
+    pub fn content_length(&self) -> Option<usize> {
+        match self.status {
+            HttpStatus::NoContent | HttpStatus::NotModified => None,
+            _ => Some(self.body.len()),
+        }
+    }
+
+    fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status.code(), self.status.reason());
+
+        for (key, value) in &self.headers {
+            head.push_str(&format!("{key}: {value}\r\n"));
+        }
+
+        if let Some(length) = self.content_length() {
+            head.push_str(&format!("Content-Length: {length}\r\n"));
+        }
+
+        head.push_str("\r\n");
+        head
+    }
+
+    // HEAD responses carry the same head as GET, including the Content-Length, just no body
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W, include_body: bool) -> Result<(), Error> {
+        writer.write_all(self.head().as_bytes())
+            .await
+            .map_err(|x| Error::io("Failed to write response head", x))?;
+
+        if include_body {
+            writer.write_all(&self.body)
+                .await
+                .map_err(|x| Error::io("Failed to write response body", x))?;
+        }
+
+        writer.flush()
+            .await
+            .map_err(|x| Error::io("Failed to flush response", x))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> Result<Option<HttpRequest>, Error> {
+        RequestReader::new(input, RequestLimits::default()).next_request().await
+    }
+
+    #[test]
+    fn server_errors_hide_details() {
+        let err = Error::Conversion("convert: unable to open image '/var/cache/emotes/x.webp'".to_owned());
+        let body = String::from_utf8(HttpResponse::from_error(&err).body).unwrap();
+
+        assert!(body.contains("Internal Server Error"));
+        assert!(! body.contains("/var/cache"));
+
+        let body = String::from_utf8(HttpResponse::from_error(&Error::NotFound("Emote not found".to_owned())).body).unwrap();
+        assert!(body.contains("Emote not found"));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b+c", false).as_deref(), Some("a b+c"));
+        assert_eq!(percent_decode("a%20b+c", true).as_deref(), Some("a b c"));
+        assert_eq!(percent_decode("%2b%2B", true).as_deref(), Some("++"));
+        assert_eq!(percent_decode("%C3%B6", false).as_deref(), Some("ö"));
+        assert_eq!(percent_decode("ö", false).as_deref(), Some("ö"));
+
+        // Broken escapes, and escapes that don't decode to UTF-8
+        for input in ["%", "%4", "%G1", "a%2", "%FF", "%C3"] {
+            assert_eq!(percent_decode(input, false), None, "{input}");
+        }
+    }
+
+    #[test]
+    fn query_parsing() {
+        let query = parse_query("x=1&x=2&flag&=empty&&na%C3%AFve=caf%C3%A9&s=a+b%2Bc&eq=a=b").unwrap();
+
+        assert_eq!(query["x"], vec!["1", "2"]);
+        assert_eq!(query["flag"], vec![""]);
+        assert_eq!(query[""], vec!["empty"]);
+        assert_eq!(query["naïve"], vec!["café"]);
+        assert_eq!(query["s"], vec!["a b+c"]);
+        assert_eq!(query["eq"], vec!["a=b"]);
+        assert_eq!(query.len(), 6);
+
+        assert!(parse_query("a=%zz").is_none());
+        assert!(parse_query("%FF=1").is_none());
+    }
+
+    #[tokio::test]
+    async fn targets_are_decoded() {
+        let request = read(b"GET /KEKW+LUL%20x/%F0%9F%98%80.gif?size=2x&size=3x&w=a+b HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.pathname, "/KEKW+LUL x/😀.gif");
+        assert_eq!(request.query_param("size"), Some("2x"));
+        assert_eq!(request.query["size"], vec!["2x", "3x"]);
+        assert_eq!(request.query_param("w"), Some("a b"));
+
+        let result = read(b"GET /a%zz HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(result, Err(Error::InvalidRequest(_))));
+
+        let result = read(b"GET /?a=%FF HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(result, Err(Error::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn chunked_body() {
+        let request = read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.body, b"abcde");
+    }
+
+    #[tokio::test]
+    async fn chunk_size_overflow_is_rejected() {
+        let result = read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nA\r\nffffffffffffffff\r\n").await;
+
+        assert!(matches!(result, Err(Error::BodyTooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn chunks_over_the_limit_are_rejected() {
+        let mut reader = RequestReader::new(
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n"[..],
+            RequestLimits { max_head_size: 1024, max_body_size: 6 },
+        );
+
+        assert!(matches!(reader.next_request().await, Err(Error::BodyTooLarge(6))));
+    }
+
+    #[tokio::test]
+    async fn repeated_framing_headers_are_rejected() {
+        let result = read(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nabcde").await;
+        assert!(matches!(result, Err(Error::InvalidRequest(_))));
+
+        let result = read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n").await;
+        assert!(matches!(result, Err(Error::InvalidRequest(_))));
+
+        let request = read(b"GET / HTTP/1.1\r\nAccept: image/gif\r\nAccept: image/png\r\n\r\n")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.header("Accept"), Some("image/png"));
+    }
+
+    #[tokio::test]
+    async fn content_length_is_digits_only() {
+        for length in ["+5", "-0", "5x", "0x5", ""] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length:{length}\r\n\r\nabcde");
+            let result = read(raw.as_bytes()).await;
+
+            assert!(matches!(result, Err(Error::InvalidRequest(_))), "{length:?}");
+        }
+
+        let request = read(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.body, b"abcde");
+    }
+
+    #[tokio::test]
+    async fn accepts_picks_the_most_specific_range() {
+        let request = read(b"GET / HTTP/1.1\r\nAccept: */*;q=0.1, IMAGE/*;q=0.5, image/webp;q=0.8, image/gif;q=0\r\n\r\n")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.accepts("image/webp"), (0.8, 2));
+        assert_eq!(request.accepts("image/gif"), (0.0, 2));
+        assert_eq!(request.accepts("image/png"), (0.5, 1));
+        assert_eq!(request.accepts("text/html"), (0.1, 0));
+
+        let request = read(b"GET / HTTP/1.1\r\nAccept: image/png; q=oops, text/html\r\n\r\n").await.unwrap().unwrap();
+        assert_eq!(request.accepts("image/png"), (1.0, 2));
+        assert_eq!(request.accepts("image/gif"), (0.0, 0));
+
+        let request = read(b"GET / HTTP/1.1\r\n\r\n").await.unwrap().unwrap();
+        assert_eq!(request.accepts("image/gif"), (1.0, 0));
+    }
 }
@@ -0,0 +1,266 @@
+use std::{collections::HashMap, io, pin::Pin, sync::Arc, task::{Context, Poll}, time::Instant};
+
+use bytes::Bytes;
+use h2::{server::{self, SendResponse}, RecvStream};
+use http::{Request, Response};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf}, sync::watch, task::JoinSet, time};
+use tracing::{error, info_span, Instrument};
+
+use crate::{
+    error::Error,
+    http::{HttpRequest, HttpResponse, HttpVerb, HttpVersion},
+    logging,
+    proxy,
+    router::Router,
+    AppState,
+    ConnectionInfo,
+};
+
+// What an h2c client sends first when it assumes the server speaks HTTP/2
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// A chat overlay loads every emote it needs at once
+const MAX_CONCURRENT_STREAMS: u32 = 128;
+
+// Only meaningful for a single HTTP/1 connection, HTTP/2 forbids them
+const CONNECTION_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+// Replays bytes that were already read off a stream before reading from it again
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if ! self.prefix.is_empty() {
+            let length = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..length]);
+            self.prefix.drain(..length);
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Reads just enough of a plain connection to tell h2c prior knowledge apart from HTTP/1,
+// the returned stream still yields everything that was read
+pub async fn sniff_preface<S: AsyncRead + Unpin>(mut stream: S) -> io::Result<(bool, Rewind<S>)> {
+    let mut prefix = Vec::with_capacity(PREFACE.len());
+    let mut chunk = [0; PREFACE.len()];
+
+    while prefix.len() < PREFACE.len() && PREFACE.starts_with(&prefix) {
+        let read = stream.read(&mut chunk[..PREFACE.len() - prefix.len()]).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        prefix.extend_from_slice(&chunk[..read]);
+    }
+
+    Ok((prefix == PREFACE, Rewind { prefix, inner: stream }))
+}
+
+fn h2_error(context: &'static str, err: h2::Error) -> Error {
+    Error::io(context, io::Error::other(err))
+}
+
+async fn read_request(request: Request<RecvStream>, max_body_size: usize) -> Result<HttpRequest, Error> {
+    let (parts, mut body) = request.into_parts();
+
+    let verb = HttpVerb::parse(parts.method.as_str())
+        .ok_or_else(|| Error::InvalidRequest("Unsupported method".to_owned()))?;
+
+    let target = parts.uri
+        .path_and_query()
+        .map(|x| x.as_str())
+        .unwrap_or("/")
+        .to_owned();
+
+    let mut headers = HashMap::<String, String>::new();
+
+    for (name, value) in &parts.headers {
+        let value = value.to_str()
+            .map_err(|_| Error::InvalidRequest(format!("Invalid {name} header")))?;
+
+        headers
+            .entry(name.as_str().to_owned())
+            .and_modify(|x| {
+                x.push_str(", ");
+                x.push_str(value);
+            })
+            .or_insert_with(|| value.to_owned());
+    }
+
+    // :authority takes the place of Host
+    if let Some(authority) = parts.uri.authority() {
+        headers.entry("host".to_owned()).or_insert_with(|| authority.to_string());
+    }
+
+    let mut content = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|x| h2_error("Failed to read request body", x))?;
+
+        if content.len() + chunk.len() > max_body_size {
+            return Err(Error::BodyTooLarge(max_body_size));
+        }
+
+        content.extend_from_slice(&chunk);
+        let _ = body.flow_control().release_capacity(chunk.len());
+    }
+
+    HttpRequest::from_parts(verb, HttpVersion::v2_0, target, headers, content)
+}
+
+fn send_response(respond: &mut SendResponse<Bytes>, response: HttpResponse, include_body: bool) -> Result<(), Error> {
+    let mut head = Response::builder().status(response.status.code());
+
+    for (key, value) in &response.headers {
+        if CONNECTION_HEADERS.iter().any(|x| key.eq_ignore_ascii_case(x)) {
+            continue;
+        }
+
+        head = head.header(key.to_ascii_lowercase(), value);
+    }
+
+    if let Some(length) = response.content_length() {
+        head = head.header("content-length", length);
+    }
+
+    let head = head.body(())
+        .map_err(|x| Error::io("Failed to build response head", io::Error::other(x)))?;
+
+    let body = Bytes::from(response.body);
+    let end_of_stream = ! include_body || body.is_empty();
+
+    let mut stream = respond.send_response(head, end_of_stream)
+        .map_err(|x| h2_error("Failed to write response head", x))?;
+
+    if ! end_of_stream {
+        stream.send_data(body, true)
+            .map_err(|x| h2_error("Failed to write response body", x))?;
+    }
+
+    Ok(())
+}
+
+async fn serve_stream(
+    state: Arc<AppState>,
+    router: Arc<Router<AppState>>,
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    connection: ConnectionInfo,
+) {
+    let max_body_size = state.config.request_limits().max_body_size;
+    let read = time::timeout(state.config.head_timeout(), read_request(request, max_body_size))
+        .await
+        .unwrap_or(Err(Error::RequestTimeout));
+
+    let http_request = match read {
+        Ok(mut http_request) => {
+            http_request.client_ip = proxy::client_ip(&http_request, connection.peer, &state.config.trusted_proxies);
+            Arc::new(http_request)
+        },
+        Err(err) => {
+            crate::log_error(&err);
+
+            if let Err(err) = send_response(&mut respond, HttpResponse::from_error(&err), true) {
+                error!("{err}");
+            }
+
+            return;
+        },
+    };
+
+    let request_id = logging::request_id();
+    let span = info_span!("request", id = %request_id);
+    let started = Instant::now();
+
+    let mut response = crate::handle_request(&state, &router, http_request.clone(), connection)
+        .instrument(span.clone())
+        .await;
+    response.set_header("X-Request-Id", &request_id);
+
+    let include_body = http_request.verb != HttpVerb::Head;
+    let bytes = if include_body { response.body.len() } else { 0 };
+    // Sending only queues the response on the connection, so this is as done as it gets
+    span.in_scope(|| logging::access(state.config.log_format, &http_request, &response, bytes, started.elapsed()));
+
+    if let Err(err) = send_response(&mut respond, response, include_body) {
+        error!("{err}");
+    }
+}
+
+// Every stream is handled concurrently, the connection itself is driven by accept()
+pub async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    state: Arc<AppState>,
+    router: Arc<Router<AppState>>,
+    mut shutdown: watch::Receiver<bool>,
+    stream: S,
+    connection: ConnectionInfo,
+) -> Result<usize, Error> {
+    let idle_timeout = state.config.idle_timeout();
+    let max_requests = state.config.max_requests_per_connection;
+
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .max_header_list_size(state.config.request_limits().max_head_size as u32)
+        .handshake(stream);
+
+    let mut h2_connection = match time::timeout(idle_timeout, handshake).await {
+        Ok(handshake) => handshake.map_err(|x| h2_error("HTTP/2 handshake failed", x))?,
+        Err(_) => return Ok(0),
+    };
+
+    let mut streams = JoinSet::new();
+    let mut served = 0;
+    // Set once GOAWAY went out, a client that never acknowledges it doesn't get to hold the connection
+    let mut closing_deadline: Option<time::Instant> = None;
+
+    loop {
+        let close_now = tokio::select! {
+            accepted = h2_connection.accept() => match accepted {
+                None => break,
+                Some(Ok((request, respond))) => {
+                    served += 1;
+                    streams.spawn(serve_stream(state.clone(), router.clone(), request, respond, connection).in_current_span());
+
+                    served >= max_requests
+                },
+                Some(Err(err)) => return Err(h2_error("HTTP/2 connection failed", err)),
+            },
+            // GOAWAY lets the client finish what's in flight and open a new connection for the rest
+            _ = shutdown.wait_for(|x| *x), if closing_deadline.is_none() => true,
+            _ = time::sleep(idle_timeout), if streams.is_empty() && closing_deadline.is_none() => true,
+            _ = time::sleep_until(closing_deadline.unwrap_or_else(time::Instant::now)), if closing_deadline.is_some() => break,
+            Some(_) = streams.join_next(), if ! streams.is_empty() => false,
+        };
+
+        if close_now && closing_deadline.is_none() {
+            h2_connection.graceful_shutdown();
+            closing_deadline = Some(time::Instant::now() + idle_timeout);
+        }
+    }
+
+    while streams.join_next().await.is_some() {}
+
+    Ok(served)
+}
@@ -0,0 +1,292 @@
+use std::{
+    env, fmt, fs, io,
+    net::{IpAddr, SocketAddr},
+    os::{fd::{FromRawFd, OwnedFd}, unix::fs::{FileTypeExt, PermissionsExt}},
+    path::PathBuf,
+    pin::Pin,
+    process,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use serde::{Deserialize, Deserializer};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+use crate::error::Error;
+
+// First descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: i32 = 3;
+const BACKLOG: i32 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    // Written as unix:/path/to.sock
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = input.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: needs a socket path".to_owned());
+            }
+
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        input.parse::<SocketAddr>()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("{input} is neither an address like 0.0.0.0:8080 or [::]:8080, nor unix:/path"))
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+// Lets config files write a single address as a plain string
+pub fn deserialize_listen<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<ListenAddr>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(ListenAddr),
+        Many(Vec<ListenAddr>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Who's on the other end, Unix socket peers have no address worth reporting
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    // The path is only set for sockets we created, and removed again on drop
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+// What systemd passes in LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES
+pub struct Activation {
+    count: i32,
+    names: String,
+}
+
+impl Activation {
+    // None when the process wasn't socket activated. The variables are removed either way so
+    // children (ImageMagick) don't think the sockets are meant for them. Changing the
+    // environment is only sound while nothing else reads it, so this has to run before
+    // the runtime starts any threads
+    pub fn take() -> Option<Activation> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+
+        let for_us = pid
+            .and_then(|x| x.parse::<u32>().ok())
+            .map(|x| x == process::id())
+            .unwrap_or(false);
+
+        match fds.and_then(|x| x.parse::<i32>().ok()) {
+            Some(count) if for_us && count > 0 => Some(Activation { count, names }),
+            _ => None,
+        }
+    }
+}
+
+impl Listener {
+    pub fn bind(addr: &ListenAddr, ipv6_only: bool, unix_socket_mode: u32) -> Result<Listener, Error> {
+        let failed = |x| Error::io(format!("Failed to listen on {addr}"), x);
+
+        match addr {
+            ListenAddr::Tcp(socket_addr) => {
+                let socket = Socket::new(Domain::for_address(*socket_addr), Type::STREAM, Some(Protocol::TCP))
+                    .map_err(failed)?;
+
+                // [::] also accepts IPv4 connections unless told otherwise
+                if socket_addr.is_ipv6() {
+                    socket.set_only_v6(ipv6_only).map_err(failed)?;
+                }
+
+                socket.set_reuse_address(true).map_err(failed)?;
+                socket.set_nonblocking(true).map_err(failed)?;
+                socket.bind(&(*socket_addr).into()).map_err(failed)?;
+                socket.listen(BACKLOG).map_err(failed)?;
+
+                TcpListener::from_std(socket.into())
+                    .map(Listener::Tcp)
+                    .map_err(failed)
+            },
+            ListenAddr::Unix(path) => {
+                // Left behind by a previous run that didn't shut down cleanly
+                let is_stale_socket = fs::symlink_metadata(path)
+                    .map(|x| x.file_type().is_socket())
+                    .unwrap_or(false);
+
+                if is_stale_socket {
+                    fs::remove_file(path).map_err(failed)?;
+                }
+
+                let listener = UnixListener::bind(path).map_err(failed)?;
+
+                fs::set_permissions(path, fs::Permissions::from_mode(unix_socket_mode))
+                    .map_err(failed)?;
+
+                Ok(Listener::Unix(listener, Some(path.clone())))
+            },
+        }
+    }
+
+    // Sockets handed over by systemd, each with its LISTEN_FDNAMES entry if there is one
+    pub fn from_activation(activation: Activation) -> Result<Vec<(Option<String>, Listener)>, Error> {
+        let Activation { count, names } = activation;
+        let mut names = names.split(':').map(|x| x.to_owned());
+        let mut listeners = Vec::new();
+
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            let failed = |x| Error::io(format!("Failed to use activated socket {fd}"), x);
+
+            // SAFETY: systemd passes these descriptors to us and nothing else in the process owns them
+            let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            socket.set_nonblocking(true).map_err(failed)?;
+            // systemd leaves them inheritable, they'd otherwise leak into every convert we spawn
+            socket.set_cloexec(true).map_err(failed)?;
+
+            let is_unix = socket.local_addr().map_err(failed)?.is_unix();
+
+            let listener = match is_unix {
+                true => Listener::Unix(UnixListener::from_std(OwnedFd::from(socket).into()).map_err(failed)?, None),
+                false => Listener::Tcp(TcpListener::from_std(socket.into()).map_err(failed)?),
+            };
+
+            let name = names.next().filter(|x| !x.is_empty());
+            listeners.push((name, listener));
+        }
+
+        Ok(listeners)
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Stream, Peer)>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, addr)| (Stream::Tcp(stream), Peer::Tcp(addr))),
+            Listener::Unix(listener, _) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| (Stream::Unix(stream), Peer::Unix)),
+        }
+    }
+
+    pub fn local_port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|x| x.port()),
+            Listener::Unix(..) => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr()
+                .map(|x| x.to_string())
+                .unwrap_or_else(|_| "unknown address".to_owned()),
+            Listener::Unix(listener, _) => listener.local_addr()
+                .ok()
+                .and_then(|x| x.as_pathname().map(|x| format!("unix:{}", x.display())))
+                .unwrap_or_else(|| "unix socket".to_owned()),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
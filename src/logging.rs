@@ -0,0 +1,136 @@
+use std::{
+    io::{self, IsTerminal},
+    str::FromStr,
+    sync::{atomic::{AtomicU64, Ordering}, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use crate::http::{HttpRequest, HttpResponse};
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // Human readable lines, the access log in Combined Log Format
+    Text,
+    // One JSON object per line with every field separate
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {input}, expected text or json")),
+        }
+    }
+}
+
+// log_level takes the same directives as RUST_LOG, like "info" or "warn,access=info"
+pub fn parse_filter(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(level).map_err(|x| x.to_string())
+}
+
+pub fn init(level: &str, format: LogFormat) {
+    let filter = parse_filter(level).expect("log_level is checked when the config is loaded");
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        // No escape codes in files and journald
+        .with_ansi(io::stdout().is_terminal());
+
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+static STARTED_AT: OnceLock<u64> = OnceLock::new();
+
+// Unique within a run, prefixed with the start time so restarts don't hand out the same IDs
+pub fn request_id() -> String {
+    let started_at = STARTED_AT.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0)
+    });
+
+    format!("{started_at:x}-{:x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+// Like 10/Oct/2000:13:55:36 +0000
+fn clf_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    let (days, day_secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        day_secs / 3600,
+        day_secs / 60 % 60,
+        day_secs % 60,
+    )
+}
+
+// One line per answered request, under the "access" target so it can be filtered on its own
+pub fn access(format: LogFormat, http_request: &HttpRequest, response: &HttpResponse, bytes: usize, duration: Duration) {
+    let client = crate::client_name(http_request);
+    let upstream_calls = http_request.upstream_calls.load(Ordering::Relaxed);
+    // Anything that didn't have to ask 7tv or Twitch was answered from the caches
+    let cache = if upstream_calls == 0 { "hit" } else { "miss" };
+    let referer = http_request.header("Referer").unwrap_or("-");
+    let user_agent = http_request.header("User-Agent").unwrap_or("-");
+    let duration_ms = duration.as_secs_f64() * 1000.0;
+
+    match format {
+        LogFormat::Text => info!(
+            target: "access",
+            "{client} - - [{}] \"{} {} {}\" {} {} \"{referer}\" \"{user_agent}\" {duration_ms:.1}ms cache={cache} upstream_calls={upstream_calls}",
+            clf_time(SystemTime::now()),
+            http_request.verb.as_str(),
+            http_request.target,
+            http_request.version.as_str(),
+            response.status.code(),
+            if bytes == 0 { "-".to_owned() } else { bytes.to_string() },
+        ),
+        LogFormat::Json => info!(
+            target: "access",
+            client,
+            method = http_request.verb.as_str(),
+            path = http_request.target,
+            version = http_request.version.as_str(),
+            status = response.status.code(),
+            bytes,
+            duration_ms,
+            cache,
+            upstream_calls,
+            referer,
+            user_agent,
+        ),
+    }
+}
@@ -1,180 +1,564 @@
+mod error;
+mod config;
+mod cors;
 mod http;
+mod http2;
 mod twitch;
 mod emote;
 mod seventv;
 mod utils;
 mod emote_puller;
-
-use std::{io::{self, BufRead}, env, sync::Arc, unimplemented, time::Duration};
-use emote_puller::{EmotePullerHandle, EmotePuller};
-use tokio::{net::{TcpListener, TcpStream}, io::{AsyncWriteExt, BufWriter, ReadBuf, BufReader, AsyncReadExt}, fs};
-use tokio::time::sleep;
+mod range;
+mod router;
+mod routes;
+mod tls;
+mod listener;
+mod proxy;
+mod rate_limit;
+mod logging;
+mod metrics;
+mod health;
+mod compose;
+
+use std::{io, env, future, process, sync::Arc, task::Poll, time::{Duration, Instant}};
+use emote_puller::EmotePullerHandle;
+use tokio::{io::{self as tokio_io, AsyncRead, AsyncWrite, BufWriter, AsyncWriteExt}, time::timeout, sync::{watch, Semaphore}, task::JoinSet, signal::{self, unix::{signal, SignalKind}}};
 use dotenv::dotenv;
+use tracing::{debug, error, info, info_span, Instrument};
 use twitch::TwitchClient;
 use emote::EmoteManagerHandle;
-// use tokio::io::BufReader;
 
-use http::HttpRequest;
+use error::Error;
+use config::Config;
+use seventv::SevenTvClient;
+use http::{HttpRequest, HttpResponse, HttpVerb, RequestReader};
+use router::Router;
+use tls::TlsReloader;
+use listener::{Activation, Listener, Peer, Stream};
+use rate_limit::RateLimiter;
+use metrics::METRICS;
+use tokio_rustls::TlsAcceptor;
+
+// Sent with the 503 for connections over max_connections, in seconds
+const OVERLOADED_RETRY_AFTER: u64 = 5;
+// Turned away connections get this long to take their 503
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+// Out of file descriptors, accepting again right away would only fail again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// errno values, the same on Linux and the BSDs
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+
+// What the requests on a connection need to know about it
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    // The client, or what a trusted PROXY protocol header said it is
+    pub peer: Peer,
+    // Plain HTTP requests are redirected to this TLS port instead of being served when set
+    pub https_redirect: Option<u16>,
+}
+
+// Shared by every connection
+pub struct AppState {
+    config: Config,
+    emote_manager: EmoteManagerHandle,
+    emote_puller: EmotePullerHandle,
+    twitch_client: TwitchClient,
+    seventv_client: Arc<SevenTvClient>,
+    request_limiter: RateLimiter,
+    upstream_limiter: Arc<RateLimiter>,
+}
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
+fn main() -> io::Result<()> {
+    // The environment is only changed here, while no other thread can be reading it
     dotenv().ok();
+    let activation = Activation::take();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(activation))
+}
+
+async fn run(activation: Option<Activation>) -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.iter().any(|x| x == "--help" || x == "-h") {
+        print!("{}", config::usage());
+        return Ok(());
+    }
+
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("[ERROR]: {err}");
+            process::exit(1);
+        },
+    };
+
+    logging::init(&config.log_level, config.log_format);
 
     let twitch_client_id = env::var("TWITCH_CLIENT_ID")
         .expect("TWITCH_CLIENT_ID env variable is present!");
     let twitch_client_secret = env::var("TWITCH_CLIENT_SECRET")
         .expect("TWITCH_CLIENT_SECRET env variable is present!");
 
-    let listener = TcpListener::bind("0.0.0.0:8080").await?;
-    let twitch_client = Arc::new(TwitchClient::new(
-        twitch_client_id,
-        twitch_client_secret,
-    ));
+    let tls = match (&config.tls.cert_path, &config.tls.key_path) {
+        (Some(cert_path), Some(key_path)) => match TlsReloader::new(cert_path.clone(), key_path.clone(), config.http2) {
+            Ok(reloader) => Some(Arc::new(reloader)),
+            Err(err) => {
+                error!("{err}");
+                process::exit(1);
+            },
+        },
+        _ => None,
+    };
 
-    let emote_manager = Arc::new(EmoteManagerHandle::new());
+    let listeners = match bind_listeners(&config, activation) {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            error!("{err}");
+            process::exit(1);
+        },
+    };
 
-    let emote_puller = Arc::new(EmotePullerHandle::new());
+    if let Some(reloader) = &tls {
+        tokio::spawn(reloader.clone().watch());
+    }
+
+    // Plain HTTP connections only get redirects when asked to, to the first TLS port there is
+    let https_redirect = match config.tls.redirect_http {
+        true => listeners.iter()
+            .filter(|(_, secure)| *secure)
+            .find_map(|(x, _)| x.local_port())
+            .or(Some(443)),
+        false => None,
+    };
+
+    let seventv_client = Arc::new(SevenTvClient::new(
+        config.seventv_api_url.clone(),
+        config.seventv_cdn_url.clone(),
+        config.upstream_request_timeout(),
+    ));
+
+    let state = Arc::new(AppState {
+        emote_manager: EmoteManagerHandle::new(&config, seventv_client.clone()),
+        emote_puller: EmotePullerHandle::new(&config, seventv_client.clone()),
+        seventv_client,
+        twitch_client: TwitchClient::new(
+            twitch_client_id,
+            twitch_client_secret,
+            config.twitch_api_url.clone(),
+            config.twitch_auth_url.clone(),
+            config.upstream_request_timeout(),
+        ),
+        request_limiter: RateLimiter::new(config.rate_limit.requests_per_minute, config.rate_limit.request_burst),
+        upstream_limiter: Arc::new(RateLimiter::new(config.rate_limit.upstream_per_minute, config.rate_limit.upstream_burst)),
+        config,
+    });
+
+    let router = Arc::new(routes::router());
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let connection_slots = Arc::new(Semaphore::new(state.config.max_connections));
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        let (mut socket, _) = listener.accept().await?;
-        let ip = match socket.peer_addr() {
+        let (accepted, secure) = tokio::select! {
+            accepted = accept_any(&listeners) => accepted,
+            _ = &mut shutdown => break,
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next(), if ! connections.is_empty() => continue,
+        };
+
+        // Failing to accept one connection is no reason to drop all the others
+        let (socket, ip) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("Failed to accept connection: {err}");
+
+                if matches!(err.raw_os_error(), Some(EMFILE | ENFILE)) {
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+
+                continue;
+            },
+        };
+
+        let slot = match connection_slots.clone().try_acquire_owned() {
+            Ok(slot) => slot,
             Err(_) => {
-                println!("[ERROR]: Failed to get client IP");
+                error!(peer = %ip, "Turning away connection, {} connection(s) open", state.config.max_connections);
+                reject_connection(socket, secure);
                 continue;
             },
-            Ok(ip) => ip,
         };
 
-        println!("[INFO]: Received request from {}", ip);
-
-        let emote_manager_instance = emote_manager.clone();
-        let twitch_client_instance = twitch_client.clone();
-        let emote_puller_instance = emote_puller.clone();
-
-        tokio::spawn(async move {
-            match serve_request(
-                emote_manager_instance,
-                emote_puller_instance,
-                twitch_client_instance,
-                &mut socket,
-            ).await {
-                Ok(_) => {
-                    println!("[INFO]: Sent response");
+        debug!(peer = %ip, "Accepted connection");
+
+        let state_instance = state.clone();
+        let router_instance = router.clone();
+        let shutdown_instance = shutdown_rx.clone();
+        let acceptor = tls.as_ref().filter(|_| secure).map(|x| x.acceptor());
+
+        connections.spawn(async move {
+            let _slot = slot;
+            let mut socket = socket;
+
+            let ip = match state_instance.config.proxy_protocol {
+                true => match proxied_peer(&state_instance, &mut socket, ip).await {
+                    Ok(ip) => ip,
+                    Err(err) => {
+                        error!("Connection failed: {err}");
+                        return;
+                    },
+                },
+                false => ip,
+            };
+
+            let connection = ConnectionInfo {
+                peer: ip,
+                https_redirect: None,
+            };
+
+            let result = match acceptor {
+                None => serve_plain(state_instance, router_instance, shutdown_instance, socket, ConnectionInfo { https_redirect, ..connection }).await,
+                Some(acceptor) => serve_tls(state_instance, router_instance, shutdown_instance, socket, acceptor, connection).await,
+            };
+
+            match result {
+                Ok(served) => {
+                    debug!("Closed connection after {served} request(s)");
                 },
                 Err(err) => {
-                    println!("[ERROR]: Failed to generate response {:?}", err);
+                    error!("Connection failed: {err}");
                 },
             }
-        });
+        }.instrument(info_span!("connection", peer = %ip)));
+    }
+
+    info!("Shutting down, waiting for {} connection(s)", connections.len());
+    drop(listeners);
+    let _ = shutdown_tx.send(true);
+
+    let drained = timeout(state.config.shutdown_deadline(), async {
+        while connections.join_next().await.is_some() {}
+
+        // Lets conversions queued by connections that already went away finish too
+        state.emote_puller.shutdown().await;
+    }).await;
+
+    if drained.is_err() {
+        error!("Shutdown deadline passed, aborting {} connection(s)", connections.len());
+        connections.shutdown().await;
+    }
+
+    info!("Shut down");
+
+    Ok(())
+}
+
+// Systemd's sockets when socket activated, otherwise the configured addresses.
+// The bool marks listeners that are served over TLS
+fn bind_listeners(config: &Config, activation: Option<Activation>) -> Result<Vec<(Listener, bool)>, Error> {
+    if let Some(activation) = activation {
+        let listeners: Vec<_> = Listener::from_activation(activation)?
+            .into_iter()
+            // Name the TLS socket with FileDescriptorName=https in the .socket unit
+            .map(|(name, listener)| {
+                let secure = matches!(name.as_deref(), Some("https" | "tls"));
+                (listener, secure && config.tls.enabled())
+            })
+            .collect();
+
+        for (listener, secure) in &listeners {
+            info!("Using activated socket {}{}", listener.describe(), if *secure { " for TLS" } else { "" });
+        }
+
+        return Ok(listeners);
+    }
+
+    let plain = config.listen.iter().map(|x| (x, false));
+    let secure = config.tls.listen.iter()
+        .filter(|_| config.tls.enabled())
+        .map(|x| (x, true));
+
+    plain.chain(secure)
+        .map(|(addr, secure)| {
+            let listener = Listener::bind(addr, config.ipv6_only, config.unix_socket_mode())?;
+            info!("Listening on {}{}", listener.describe(), if secure { " for TLS" } else { "" });
+
+            Ok((listener, secure))
+        })
+        .collect()
+}
+
+async fn accept_any(listeners: &[(Listener, bool)]) -> (io::Result<(Stream, Peer)>, bool) {
+    future::poll_fn(|cx| {
+        for (listener, secure) in listeners {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready((accepted, *secure));
+            }
+        }
+
+        Poll::Pending
+    }).await
+}
+
+// Plain connections are told to come back later, TLS ones can't be answered without a handshake
+fn reject_connection(socket: Stream, secure: bool) {
+    if secure {
+        return;
     }
+
+    tokio::spawn(async move {
+        let mut socket = socket;
+        let mut response = HttpResponse::from_error(&Error::Overloaded(OVERLOADED_RETRY_AFTER));
+        response.set_header("Connection", "close");
+
+        let _ = timeout(REJECT_WRITE_TIMEOUT, async {
+            response.write_to(&mut socket, true).await?;
+            socket.shutdown().await.map_err(|x| Error::io("Failed to close connection", x))
+        }).await;
+    });
 }
 
-struct ParsedRequest {
-    emotes: Vec<String>,
-    twitch_username: Option<String>,
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate())
+        .expect("Should be able to listen for SIGTERM");
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
 }
 
-fn parse_request(pathname: &str) -> Option<ParsedRequest> {
-    let parts: Vec<String> = pathname.trim_matches('/').split('/').map(|x| x.to_owned()).collect();
+// The PROXY protocol header comes before anything else, TLS included. Only trusted
+// proxies get to say who the client is, the header is consumed either way
+async fn proxied_peer(state: &AppState, socket: &mut Stream, peer: Peer) -> Result<Peer, Error> {
+    let header = timeout(state.config.idle_timeout(), proxy::read_header(socket))
+        .await
+        .map_err(|_| Error::io("PROXY protocol header timed out", io::ErrorKind::TimedOut.into()))??;
+
+    match header {
+        Some(source) if proxy::is_trusted(peer, &state.config.trusted_proxies) => {
+            debug!("Connection from {peer} is proxied for {source}");
+            Ok(Peer::Tcp(source))
+        },
+        Some(_) => {
+            error!("Ignoring PROXY protocol header from untrusted {peer}");
+            Ok(peer)
+        },
+        None => Ok(peer),
+    }
+}
 
-    if parts.len() == 1 {
-        let emote = parts.get(0)?.to_owned().trim_end_matches(".gif").to_owned();
+async fn serve_tls(
+    state: Arc<AppState>,
+    router: Arc<Router<AppState>>,
+    shutdown: watch::Receiver<bool>,
+    socket: Stream,
+    acceptor: TlsAcceptor,
+    connection: ConnectionInfo,
+) -> Result<usize, Error> {
+    let stream = match timeout(state.config.idle_timeout(), acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => return Err(Error::io("TLS handshake failed", err)),
+        Err(_) => return Err(Error::io("TLS handshake timed out", io::ErrorKind::TimedOut.into())),
+    };
 
-        return Some(ParsedRequest {
-            emotes: vec![emote],
-            twitch_username: None,
-        });
+    if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        return http2::serve_connection(state, router, shutdown, stream, connection).await;
     }
 
-    if parts.len() == 2 {
-        let username = parts.get(0)?.to_owned();
-        let emote = parts.get(0)?.to_owned().trim_end_matches(".gif").to_owned();
+    serve_connection(state, router, shutdown, stream, connection).await
+}
 
-        return Some(ParsedRequest {
-            emotes: vec![emote],
-            twitch_username: Some(username),
-        });
+// Plain connections speak HTTP/1 unless they open with the HTTP/2 preface
+async fn serve_plain(
+    state: Arc<AppState>,
+    router: Arc<Router<AppState>>,
+    mut shutdown: watch::Receiver<bool>,
+    socket: Stream,
+    connection: ConnectionInfo,
+) -> Result<usize, Error> {
+    if ! state.config.http2 {
+        return serve_connection(state, router, shutdown, socket, connection).await;
     }
 
-    None
+    let sniffed = tokio::select! {
+        sniffed = timeout(state.config.idle_timeout(), http2::sniff_preface(socket)) => sniffed,
+        _ = shutdown.wait_for(|x| *x) => return Ok(0),
+    };
+
+    match sniffed {
+        Err(_) => Ok(0),
+        Ok(Err(err)) => Err(Error::io("Failed to read request", err)),
+        Ok(Ok((true, stream))) => http2::serve_connection(state, router, shutdown, stream, connection).await,
+        Ok(Ok((false, stream))) => serve_connection(state, router, shutdown, stream, connection).await,
+    }
 }
 
-async fn serve_request(
-    emote_manager: Arc<EmoteManagerHandle>,
-    emote_puller: Arc<EmotePullerHandle>,
-    twitch_client: Arc<TwitchClient>,
-    stream: &mut TcpStream,
-) -> Result<(), String> {
-    let (raw_reader, raw_writer) = stream.split();
-    let mut reader = BufReader::new(raw_reader);
-    let mut buffer: Vec<u8> = vec![0; 1000];
-    reader.read(&mut buffer)
-        .await
-        .map_err(|_| "Failed to read request data".to_owned())?;
+// Everything between a parsed request and the response, regardless of the protocol version
+pub async fn handle_request(
+    state: &Arc<AppState>,
+    router: &Router<AppState>,
+    http_request: Arc<HttpRequest>,
+    connection: ConnectionInfo,
+) -> HttpResponse {
+    let started = Instant::now();
+
+    let mut response = if let Some(https_port) = connection.https_redirect {
+        tls::redirect_response(&http_request, https_port)
+    } else if http_request.verb == HttpVerb::Options {
+        state.config.cors.preflight(&http_request)
+    } else {
+        let dispatched = match state.request_limiter.check(http_request.client_ip) {
+            Ok(()) => router.dispatch(state.clone(), http_request.clone()).await,
+            Err(err) => Err(err),
+        };
 
-    let mut writer = BufWriter::new(raw_writer);
+        match dispatched {
+            Ok(response) => response,
+            Err(err) => {
+                log_error(&err);
+                HttpResponse::from_error(&err)
+            },
+        }
+    };
 
+    state.config.cors.apply(&http_request, &mut response);
 
-    // sleep(Duration::from_secs(2)).await;
+    let route = router.pattern_for(&http_request.pathname).unwrap_or("unmatched");
+    METRICS.http_requests.inc(&[("route", route), ("status", &response.status.code().to_string())]);
+    METRICS.http_request_duration.observe(&[("route", route)], started.elapsed().as_secs_f64());
 
-    let http_request = HttpRequest::parse(&buffer)?;
+    response
+}
 
-    let ParsedRequest { emotes, twitch_username } = parse_request(&http_request.pathname)
-        .ok_or("[ERROR]: Couldn't parse request pathname".to_owned())?;
+// Client mistakes and rate limiting are routine, only our own failures are errors
+pub fn log_error(err: &Error) {
+    if err.status().code() >= 500 {
+        error!("{err}");
+    } else {
+        debug!("{err}");
+    }
+}
 
-    println!("{:?}", emotes);
+// For logs, a "-" stands for a Unix socket peer nothing was forwarded for
+pub fn client_name(http_request: &HttpRequest) -> String {
+    http_request.client_ip
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "-".to_owned())
+}
 
-    let emote_keyword = emotes.into_iter().nth(0).unwrap();
+// A client that stops reading doesn't get to hold the connection open
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &HttpResponse,
+    include_body: bool,
+    write_timeout: Duration,
+) -> Result<(), Error> {
+    timeout(write_timeout, response.write_to(writer, include_body))
+        .await
+        .map_err(|_| Error::io("Writing the response timed out", io::ErrorKind::TimedOut.into()))?
+}
 
-    let emote = async {
-        if let Some(username) = twitch_username {
-            let twitch_id = match twitch_client.get_id_for_username(&username).await {
-                Ok(twitch_id) => twitch_id,
-                Err(err) => {
-                    println!("[ERROR]: Something happened while getting twitch id for username {username} {err}");
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    state: Arc<AppState>,
+    router: Arc<Router<AppState>>,
+    mut shutdown: watch::Receiver<bool>,
+    stream: S,
+    connection: ConnectionInfo,
+) -> Result<usize, Error> {
+    let (raw_reader, raw_writer) = tokio_io::split(stream);
+    let mut requests = RequestReader::new(raw_reader, state.config.request_limits());
+    let idle_timeout = state.config.idle_timeout();
+    let head_timeout = state.config.head_timeout();
+    let write_timeout = state.config.write_timeout();
+    let max_requests = state.config.max_requests_per_connection;
+    let mut writer = BufWriter::new(raw_writer);
+    let mut served = 0;
 
-                    return Err("Failed to get twitch ID".to_owned());
-                },
-            };
+    while served < max_requests {
+        let waited = tokio::select! {
+            waited = timeout(idle_timeout, requests.wait_for_request()) => waited,
+            // Connections waiting for their next request are simply closed on shutdown
+            _ = shutdown.wait_for(|x| *x) => break,
+        };
 
-            let emote = emote_manager.get_user_emote(&twitch_id, &emote_keyword).await?;
-            Ok(emote)
-        } else {
-            let emote = emote_manager.get_popular_emote(&emote_keyword).await?;
-            Ok(emote)
+        match waited {
+            Err(_) | Ok(Ok(false)) => break,
+            Ok(Ok(true)) => {},
+            Ok(Err(err)) => return Err(err),
         }
-    }.await?;
 
-    emote_puller.pull_emote(emote.clone()).await?;
+        // Once a request started it has to arrive in time, however slowly its bytes trickle in
+        let next_request = timeout(head_timeout, requests.next_request())
+            .await
+            .unwrap_or(Err(Error::RequestTimeout));
+
+        let http_request = match next_request {
+            Ok(None) => break,
+            Ok(Some(http_request)) => http_request,
+            Err(err) => {
+                // The framing is lost at this point so the connection can't be reused
+                log_error(&err);
+                let mut response = HttpResponse::from_error(&err);
+                response.set_header("Connection", "close");
+                write_response(&mut writer, &response, true, write_timeout).await?;
+                served += 1;
+                break;
+            },
+        };
 
-    let mime = {
-        if emote.animated {
-            "image/gif"
+        served += 1;
+        let mut http_request = http_request;
+        http_request.client_ip = proxy::client_ip(&http_request, connection.peer, &state.config.trusted_proxies);
+        let http_request = Arc::new(http_request);
+
+        let request_id = logging::request_id();
+        let span = info_span!("request", id = %request_id);
+        let started = Instant::now();
+
+        let mut response = handle_request(&state, &router, http_request.clone(), connection)
+            .instrument(span.clone())
+            .await;
+        response.set_header("X-Request-Id", &request_id);
+
+        // Checked after handling so a shutdown that came in meanwhile closes the connection
+        let keep_alive = http_request.keep_alive()
+            && served < max_requests
+            && ! *shutdown.borrow();
+
+        if keep_alive {
+            response.set_header("Connection", "keep-alive");
+            response.set_header("Keep-Alive", &format!(
+                "timeout={}, max={}",
+                idle_timeout.as_secs(),
+                max_requests - served,
+            ));
         } else {
-            "image/webp"
+            response.set_header("Connection", "close");
         }
-    };
 
-    println!("Reading emote file");
-    println!("{:?}", EmotePuller::get_pulled_emote_path(&emote) );
-    let emote_file = fs::read(EmotePuller::get_pulled_emote_path(&emote))
-        .await
-        .map_err(|x| x.to_string())?;
-    let emote_size = emote_file.len();
-    println!("File size {emote_size}");
+        let include_body = http_request.verb != HttpVerb::Head;
+        write_response(&mut writer, &response, include_body, write_timeout).await?;
 
-    // Refactor into HttpResponse struct
-    let response_head = format!("HTTP/1.1 200 OK\r\nContent-Type: {mime}\r\nContent-Length: {emote_size}\r\n\r\n");
-    writer.write(response_head.as_bytes())
-        .await
-        .map_err(|x| x.to_string())?;
-    writer.write_all(&emote_file)
-        .await
-        .map_err(|x| x.to_string())?;
+        let bytes = if include_body { response.body.len() } else { 0 };
+        span.in_scope(|| logging::access(state.config.log_format, &http_request, &response, bytes, started.elapsed()));
+
+        if ! keep_alive {
+            break;
+        }
+    }
 
-    writer.shutdown()
+    timeout(write_timeout, writer.shutdown())
         .await
-        .map_err(|x| x.to_string())?;
+        .map_err(|_| Error::io("Closing the connection timed out", io::ErrorKind::TimedOut.into()))?
+        .map_err(|x| Error::io("Failed to close connection", x))?;
 
-    Ok(())
+    Ok(served)
 }
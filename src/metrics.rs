@@ -0,0 +1,170 @@
+use std::{collections::BTreeMap, fmt::Write, future::Future, sync::Mutex, time::Instant};
+
+use crate::error::{Error, Upstream};
+
+// Seconds, from cache hits up to upstream calls running into their timeout
+const DURATION_BUCKETS: [f64; 13] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+pub struct Histogram {
+    // Cumulative, one per bucket bound
+    counts: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+// Every series of one metric, keyed by their label values
+pub struct Family<T> {
+    name: &'static str,
+    help: &'static str,
+    series: Mutex<BTreeMap<Labels, T>>,
+}
+
+impl<T: Default> Family<T> {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self { name, help, series: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn update(&self, labels: &[(&'static str, &str)], update: impl FnOnce(&mut T)) {
+        let labels = labels.iter().map(|(key, value)| (*key, value.to_string())).collect();
+        let mut series = self.series.lock().expect("Metrics lock shouldn't be poisoned");
+
+        update(series.entry(labels).or_default());
+    }
+
+    fn render_header(&self, out: &mut String, kind: &str) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {kind}", self.name);
+    }
+}
+
+impl Family<u64> {
+    pub fn inc(&self, labels: &[(&'static str, &str)]) {
+        self.update(labels, |x| *x += 1);
+    }
+
+    fn render(&self, out: &mut String) {
+        self.render_header(out, "counter");
+
+        for (labels, value) in self.series.lock().expect("Metrics lock shouldn't be poisoned").iter() {
+            let _ = writeln!(out, "{}{} {value}", self.name, format_labels(labels, None));
+        }
+    }
+}
+
+impl Family<Histogram> {
+    pub fn observe(&self, labels: &[(&'static str, &str)], seconds: f64) {
+        self.update(labels, |histogram| {
+            for (count, bound) in histogram.counts.iter_mut().zip(DURATION_BUCKETS) {
+                if seconds <= bound {
+                    *count += 1;
+                }
+            }
+
+            histogram.sum += seconds;
+            histogram.count += 1;
+        });
+    }
+
+    fn render(&self, out: &mut String) {
+        self.render_header(out, "histogram");
+
+        for (labels, histogram) in self.series.lock().expect("Metrics lock shouldn't be poisoned").iter() {
+            for (count, bound) in histogram.counts.iter().zip(DURATION_BUCKETS) {
+                let _ = writeln!(out, "{}_bucket{} {count}", self.name, format_labels(labels, Some(&bound.to_string())));
+            }
+
+            let _ = writeln!(out, "{}_bucket{} {}", self.name, format_labels(labels, Some("+Inf")), histogram.count);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, format_labels(labels, None), histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, format_labels(labels, None), histogram.count);
+        }
+    }
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{key}=\"{value}\"")
+        })
+        .collect();
+
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+
+    match pairs.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", pairs.join(",")),
+    }
+}
+
+pub struct Metrics {
+    pub http_requests: Family<u64>,
+    pub http_request_duration: Family<Histogram>,
+    pub emote_cache: Family<u64>,
+    pub emote_downloads: Family<u64>,
+    pub emote_conversion_duration: Family<Histogram>,
+    pub emote_conversion_failures: Family<u64>,
+    pub twitch_token_refreshes: Family<u64>,
+    pub upstream_duration: Family<Histogram>,
+    pub upstream_errors: Family<u64>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    http_requests: Family::new("thirdpartything_http_requests_total", "Answered requests by route and status"),
+    http_request_duration: Family::new("thirdpartything_http_request_duration_seconds", "Time from a parsed request to its response"),
+    emote_cache: Family::new("thirdpartything_emote_cache_total", "Cache lookups by cache and result (hit, miss, refresh)"),
+    emote_downloads: Family::new("thirdpartything_emote_downloads_total", "Emote files pulled from the 7tv CDN by result"),
+    emote_conversion_duration: Family::new("thirdpartything_emote_conversion_duration_seconds", "Time spent converting animated emotes"),
+    emote_conversion_failures: Family::new("thirdpartything_emote_conversion_failures_total", "Emote conversions that failed"),
+    twitch_token_refreshes: Family::new("thirdpartything_twitch_token_refreshes_total", "Twitch app access tokens fetched"),
+    upstream_duration: Family::new("thirdpartything_upstream_request_duration_seconds", "Latency of calls to 7tv and Twitch"),
+    upstream_errors: Family::new("thirdpartything_upstream_errors_total", "Calls to 7tv and Twitch that failed or timed out"),
+};
+
+// Times a call to an upstream. Not found answers are answers, only failures count as errors
+pub async fn upstream_call<T>(
+    upstream: Upstream,
+    call: &'static str,
+    request: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let started = Instant::now();
+    let result = request.await;
+    let upstream = upstream.to_string().to_lowercase();
+    let labels = [("upstream", upstream.as_str()), ("call", call)];
+
+    METRICS.upstream_duration.observe(&labels, started.elapsed().as_secs_f64());
+
+    if result.as_ref().is_err_and(|x| x.is_upstream_failure()) {
+        METRICS.upstream_errors.inc(&labels);
+    }
+
+    result
+}
+
+// Gauges are sampled by the caller at scrape time, as (actor, queued messages)
+pub fn render(mailbox_depths: &[(&str, usize)]) -> String {
+    let mut out = String::new();
+
+    METRICS.http_requests.render(&mut out);
+    METRICS.http_request_duration.render(&mut out);
+    METRICS.emote_cache.render(&mut out);
+    METRICS.emote_downloads.render(&mut out);
+    METRICS.emote_conversion_duration.render(&mut out);
+    METRICS.emote_conversion_failures.render(&mut out);
+    METRICS.twitch_token_refreshes.render(&mut out);
+    METRICS.upstream_duration.render(&mut out);
+    METRICS.upstream_errors.render(&mut out);
+
+    let _ = writeln!(out, "# HELP thirdpartything_actor_mailbox_depth Messages waiting in an actor's queue");
+    let _ = writeln!(out, "# TYPE thirdpartything_actor_mailbox_depth gauge");
+
+    for (actor, depth) in mailbox_depths {
+        let _ = writeln!(out, "thirdpartything_actor_mailbox_depth{{actor=\"{actor}\"}} {depth}");
+    }
+
+    out
+}
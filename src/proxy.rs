@@ -0,0 +1,384 @@
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, str::FromStr};
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{error::Error, http::HttpRequest, listener::Peer};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// "PROXY TCP6 <39 chars> <39 chars> 65535 65535\r\n" is the longest possible v1 line
+const V1_MAX_LENGTH: usize = 107;
+// The address block is at most 216 bytes, the rest would be TLVs we don't use anyway
+const V2_MAX_LENGTH: usize = 4096;
+
+// An address or a CIDR block like 10.0.0.0/8 or fd00::/8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Shifting by the full width means a /0, which matches everything
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{input} is neither an IP address nor a CIDR block");
+
+        let (addr, prefix) = match input.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (input, None),
+        };
+
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|x| *x <= max_prefix).ok_or_else(invalid)?,
+            None => max_prefix,
+        };
+
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+// Unix socket peers can only be local processes, so they're always trusted
+pub fn is_trusted(peer: Peer, trusted: &[IpNet]) -> bool {
+    match peer.ip() {
+        Some(ip) => trusted.iter().any(|x| x.contains(ip)),
+        None => true,
+    }
+}
+
+// Accepts "192.0.2.1", "192.0.2.1:80", "[2001:db8::1]:80" and "2001:db8::1"
+fn parse_node(input: &str) -> Option<IpAddr> {
+    let input = input.trim().trim_matches('"');
+
+    input.parse::<IpAddr>().ok()
+        .or_else(|| input.parse::<SocketAddr>().ok().map(|x| x.ip()))
+        .or_else(|| input.strip_prefix('[').and_then(|x| x.strip_suffix(']')).and_then(|x| x.parse().ok()))
+}
+
+// Hops from the original client to the last proxy, None for entries that aren't addresses
+fn forwarded_chain(request: &HttpRequest) -> Vec<Option<IpAddr>> {
+    if let Some(forwarded) = request.header("Forwarded") {
+        return forwarded
+            .split(',')
+            .map(|element| element
+                .split(';')
+                .filter_map(|x| x.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value)))
+            .collect();
+    }
+
+    request.header("X-Forwarded-For")
+        .map(|x| x.split(',').map(parse_node).collect())
+        .unwrap_or_default()
+}
+
+// The address of whoever sent the request. Forwarding headers are walked back from
+// the closest hop for as long as the hops are trusted proxies, so clients can't
+// spoof their address by sending the headers themselves
+pub fn client_ip(request: &HttpRequest, peer: Peer, trusted: &[IpNet]) -> Option<IpAddr> {
+    let mut client = peer.ip();
+
+    if ! is_trusted(peer, trusted) {
+        return client;
+    }
+
+    for hop in forwarded_chain(request).into_iter().rev() {
+        // Obfuscated or garbled, nothing before it can be relied on
+        let ip = match hop {
+            Some(ip) => ip,
+            None => break,
+        };
+
+        client = Some(ip);
+
+        if ! trusted.iter().any(|x| x.contains(ip)) {
+            break;
+        }
+    }
+
+    client
+}
+
+async fn read_exact<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut [u8]) -> Result<(), Error> {
+    stream.read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(|x| Error::io("Failed to read PROXY protocol header", x))
+}
+
+fn parse_v1(line: &str) -> Option<Option<SocketAddr>> {
+    let mut parts = line.split(' ');
+
+    if parts.next()? != "PROXY" {
+        return None;
+    }
+
+    match parts.next()? {
+        "UNKNOWN" => Some(None),
+        "TCP4" | "TCP6" => {
+            let source = parts.next()?.parse::<IpAddr>().ok()?;
+            let _destination = parts.next()?.parse::<IpAddr>().ok()?;
+            let source_port = parts.next()?.parse::<u16>().ok()?;
+
+            Some(Some(SocketAddr::new(source, source_port)))
+        },
+        _ => None,
+    }
+}
+
+fn parse_v2(command: u8, family: u8, block: &[u8]) -> Option<Option<SocketAddr>> {
+    // LOCAL connections are the proxy's own health checks
+    if command == 0x20 {
+        return Some(None);
+    }
+
+    if command != 0x21 {
+        return None;
+    }
+
+    match family >> 4 {
+        0x1 if block.len() >= 12 => {
+            let source = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+
+            Some(Some(SocketAddr::new(IpAddr::V4(source), port)))
+        },
+        0x2 if block.len() >= 36 => {
+            let source: [u8; 16] = block[0..16].try_into().ok()?;
+            let port = u16::from_be_bytes([block[32], block[33]]);
+
+            Some(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(source)), port)))
+        },
+        // AF_UNSPEC and AF_UNIX carry nothing we could report
+        _ => Some(None),
+    }
+}
+
+// Reads a PROXY protocol v1 or v2 header off the start of a connection, without
+// consuming anything past it. None means the proxy didn't know the client's address
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, Error> {
+    let invalid = || Error::InvalidRequest("Invalid PROXY protocol header".to_owned());
+
+    // Shorter than the shortest header of either version
+    let mut start = [0; 12];
+    read_exact(stream, &mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        read_exact(stream, &mut fixed).await?;
+
+        let length = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+
+        if length > V2_MAX_LENGTH {
+            return Err(invalid());
+        }
+
+        let mut block = vec![0; length];
+        read_exact(stream, &mut block).await?;
+
+        return parse_v2(fixed[0], fixed[1], &block).ok_or_else(invalid);
+    }
+
+    if ! start.starts_with(b"PROXY ") {
+        return Err(invalid());
+    }
+
+    let mut line = start.to_vec();
+
+    // Byte by byte so nothing after the header gets consumed, it's read once per connection
+    while ! line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid());
+        }
+
+        let mut byte = [0; 1];
+        read_exact(stream, &mut byte).await?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid())?;
+
+    parse_v1(line).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{RequestLimits, RequestReader};
+
+    async fn with_headers(headers: &str) -> HttpRequest {
+        let raw = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+
+        RequestReader::new(raw.as_bytes(), RequestLimits::default())
+            .next_request()
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn peer(ip: &str) -> Peer {
+        Peer::Tcp(SocketAddr::new(ip.parse().unwrap(), 40000))
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+    }
+
+    fn v2(command: u8, family: u8, block: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend((block.len() as u16).to_be_bytes());
+        header.extend(block);
+        header
+    }
+
+    #[test]
+    fn ip_net_contains() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(! net.contains("11.0.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains("203.0.113.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    }
+
+    #[tokio::test]
+    async fn untrusted_peers_cant_spoof_their_address() {
+        let request = with_headers("X-Forwarded-For: 198.51.100.7\r\nForwarded: for=198.51.100.8\r\n").await;
+
+        assert_eq!(client_ip(&request, peer("203.0.113.5"), &trusted()), ip("203.0.113.5"));
+    }
+
+    #[tokio::test]
+    async fn trusted_hops_are_walked_back() {
+        let request = with_headers("X-Forwarded-For: 198.51.100.7, 10.0.0.2\r\n").await;
+        assert_eq!(client_ip(&request, peer("10.0.0.1"), &trusted()), ip("198.51.100.7"));
+
+        let request = with_headers("X-Forwarded-For: 10.0.0.3, 10.0.0.2\r\n").await;
+        assert_eq!(client_ip(&request, peer("10.0.0.1"), &trusted()), ip("10.0.0.3"));
+
+        // Unix socket peers are local and trusted
+        let request = with_headers("X-Forwarded-For: 198.51.100.7\r\n").await;
+        assert_eq!(client_ip(&request, Peer::Unix, &trusted()), ip("198.51.100.7"));
+    }
+
+    #[tokio::test]
+    async fn mixed_chains_stop_at_the_first_untrusted_hop() {
+        // The client made up 192.0.2.1, the first untrusted hop is all that can be relied on
+        let request = with_headers("X-Forwarded-For: 192.0.2.1, 198.51.100.7, 10.0.0.2\r\n").await;
+
+        assert_eq!(client_ip(&request, peer("10.0.0.1"), &trusted()), ip("198.51.100.7"));
+    }
+
+    #[tokio::test]
+    async fn forwarded_header() {
+        let request = with_headers("Forwarded: for=192.0.2.60;proto=http, for=\"[fd00::2]:4711\"\r\nX-Forwarded-For: 198.51.100.7\r\n").await;
+        assert_eq!(client_ip(&request, peer("10.0.0.1"), &trusted()), ip("192.0.2.60"));
+
+        let request = with_headers("Forwarded: for=unknown\r\n").await;
+        assert_eq!(client_ip(&request, peer("10.0.0.1"), &trusted()), ip("10.0.0.1"));
+
+        // Nothing before an obfuscated hop can be trusted
+        let request = with_headers("Forwarded: for=192.0.2.60, for=_hidden, for=10.0.0.2\r\n").await;
+        assert_eq!(client_ip(&request, peer("10.0.0.1"), &trusted()), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn v1_lines() {
+        assert_eq!(parse_v1("PROXY TCP4 192.0.2.1 192.0.2.2 5000 443"), Some(Some("192.0.2.1:5000".parse().unwrap())));
+        assert_eq!(parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 5000 443"), Some(Some("[2001:db8::1]:5000".parse().unwrap())));
+        assert_eq!(parse_v1("PROXY UNKNOWN"), Some(None));
+        assert_eq!(parse_v1("PROXY TCP4 192.0.2.1 192.0.2.2 99999 443"), None);
+        assert_eq!(parse_v1("PROXY UDP4 192.0.2.1 192.0.2.2 5000 443"), None);
+    }
+
+    #[test]
+    fn v2_blocks() {
+        let block = [192, 0, 2, 1, 192, 0, 2, 2, 0x13, 0x88, 0x01, 0xbb];
+
+        assert_eq!(parse_v2(0x21, 0x11, &block), Some(Some("192.0.2.1:5000".parse().unwrap())));
+        assert_eq!(parse_v2(0x20, 0x00, &[]), Some(None));
+        assert_eq!(parse_v2(0x21, 0x11, &block[..8]), Some(None));
+        assert_eq!(parse_v2(0x22, 0x11, &block), None);
+    }
+
+    #[tokio::test]
+    async fn headers_are_read_without_consuming_the_request() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 5000 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("192.0.2.1:5000".parse().unwrap()));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut input = v2(0x21, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0x13, 0x88, 0x01, 0xbb]);
+        input.extend(b"GET");
+        let mut stream = input.as_slice();
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("192.0.2.1:5000".parse().unwrap()));
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_local_has_no_address() {
+        let input = v2(0x20, 0x00, &[]);
+
+        assert_eq!(read_header(&mut input.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_rejected() {
+        assert!(read_header(&mut &b"PROXY TCP4 192.0.2.1"[..]).await.is_err());
+        assert!(read_header(&mut &b"\r\n\r\n\0\r\nQUIT\n\x21\x11"[..]).await.is_err());
+
+        let input = v2(0x21, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0x13, 0x88, 0x01, 0xbb]);
+        assert!(read_header(&mut &input[..input.len() - 4]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_rejected() {
+        let line = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH));
+        assert!(read_header(&mut line.as_bytes()).await.is_err());
+
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x11, 0xff, 0xff]);
+        input.extend(vec![0; 0xffff]);
+        assert!(read_header(&mut input.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn other_protocols_are_rejected() {
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).await.is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::{HttpResponse, HttpStatus};
+
+// More ranges than this in one request is almost certainly abuse, serve the whole thing instead
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: usize,
+    pub end: usize, // inclusive
+}
+
+impl ByteRange {
+    fn content_range(&self, length: usize) -> String {
+        format!("bytes {}-{}/{length}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+fn parse_range_spec(spec: &str, length: usize) -> Option<Option<ByteRange>> {
+    let (start, end) = spec.trim().split_once('-')?;
+
+    // "-500" is the last 500 bytes
+    if start.is_empty() {
+        let suffix = end.parse::<usize>().ok()?;
+
+        if suffix == 0 || length == 0 {
+            return Some(None);
+        }
+
+        return Some(Some(ByteRange {
+            start: length.saturating_sub(suffix),
+            end: length - 1,
+        }));
+    }
+
+    let start = start.parse::<usize>().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse::<usize>().ok()?),
+    };
+
+    if end.map(|end| end < start).unwrap_or(false) {
+        return None;
+    }
+
+    if start >= length {
+        return Some(None);
+    }
+
+    Some(Some(ByteRange {
+        start,
+        end: end.unwrap_or(length - 1).min(length - 1),
+    }))
+}
+
+// Syntactically invalid headers are ignored as RFC 9110 asks, which means serving the full body
+pub fn parse_range(header: &str, length: usize) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    let mut spec_count = 0;
+
+    for spec in specs.split(',').filter(|x| !x.trim().is_empty()) {
+        spec_count += 1;
+
+        match parse_range_spec(spec, length) {
+            None => return RangeRequest::Full,
+            Some(None) => {},
+            Some(Some(range)) => ranges.push(range),
+        }
+    }
+
+    if spec_count == 0 || spec_count > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    RangeRequest::Partial(ranges)
+}
+
+pub fn unsatisfiable_response(length: usize) -> HttpResponse {
+    let mut response = HttpResponse::error(HttpStatus::RangeNotSatisfiable, "Requested range not satisfiable");
+    response.set_header("Content-Range", &format!("bytes */{length}"));
+    response
+}
+
+pub fn partial_response(ranges: &[ByteRange], mime: &str, body: &[u8]) -> HttpResponse {
+    let length = body.len();
+
+    if let [range] = ranges {
+        let mut response = HttpResponse::new(HttpStatus::PartialContent)
+            .with_body(mime, body[range.start..=range.end].to_vec());
+        response.set_header("Content-Range", &range.content_range(length));
+
+        return response;
+    }
+
+    let boundary = format!(
+        "{:032x}",
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_nanos()).unwrap_or(0),
+    );
+    let mut multipart = Vec::new();
+
+    for range in ranges {
+        multipart.extend_from_slice(format!(
+            "\r\n--{boundary}\r\nContent-Type: {mime}\r\nContent-Range: {}\r\n\r\n",
+            range.content_range(length),
+        ).as_bytes());
+        multipart.extend_from_slice(&body[range.start..=range.end]);
+    }
+
+    multipart.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    HttpResponse::new(HttpStatus::PartialContent)
+        .with_body(&format!("multipart/byteranges; boundary={boundary}"), multipart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: usize, end: usize) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse_range_spec("-500", 1000), Some(Some(range(500, 999))));
+        // Longer than the body is the whole body
+        assert_eq!(parse_range_spec("-5000", 1000), Some(Some(range(0, 999))));
+        assert_eq!(parse_range_spec("-0", 1000), Some(None));
+        assert_eq!(parse_range_spec("-x", 1000), None);
+
+        assert_eq!(parse_range("bytes=-100", 1000), RangeRequest::Partial(vec![range(900, 999)]));
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn bounded_and_open_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), RangeRequest::Partial(vec![range(0, 99)]));
+        assert_eq!(parse_range("bytes=900-", 1000), RangeRequest::Partial(vec![range(900, 999)]));
+        assert_eq!(parse_range("bytes=900-5000", 1000), RangeRequest::Partial(vec![range(900, 999)]));
+        assert_eq!(
+            parse_range("bytes=0-0, 10-19", 1000),
+            RangeRequest::Partial(vec![range(0, 0), range(10, 19)]),
+        );
+    }
+
+    #[test]
+    fn zero_length_body() {
+        assert_eq!(parse_range_spec("0-", 0), Some(None));
+        assert_eq!(parse_range_spec("-10", 0), Some(None));
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn start_past_the_end_is_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        // One satisfiable range is enough for the others to be dropped
+        assert_eq!(parse_range("bytes=2000-, 0-9", 1000), RangeRequest::Partial(vec![range(0, 9)]));
+    }
+
+    #[test]
+    fn end_before_start_is_ignored() {
+        assert_eq!(parse_range_spec("10-5", 1000), None);
+        assert_eq!(parse_range("bytes=10-5", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=0-9, 10-5", 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn invalid_headers_are_ignored() {
+        assert_eq!(parse_range("items=0-9", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=abc", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=0-x", 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn too_many_ranges() {
+        let specs = |count: usize| (0..count).map(|x| format!("{x}-{x}")).collect::<Vec<_>>().join(",");
+
+        assert!(matches!(
+            parse_range(&format!("bytes={}", specs(MAX_RANGES)), 1000),
+            RangeRequest::Partial(ranges) if ranges.len() == MAX_RANGES,
+        ));
+        assert_eq!(parse_range(&format!("bytes={}", specs(MAX_RANGES + 1)), 1000), RangeRequest::Full);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{collections::HashMap, net::IpAddr, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use serde::Deserialize;
+
+use crate::error::Error;
+
+// Buckets that filled up again are forgotten this often, they'd start out full anyway
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Every request a client makes, cache hits included. 0 turns the limit off
+    pub requests_per_minute: f64,
+    pub request_burst: u32,
+    // Calls to 7tv or Twitch made for the client, only cache misses make any
+    pub upstream_per_minute: f64,
+    pub upstream_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 600.0,
+            // A chat overlay loads every emote it needs at once
+            request_burst: 200,
+            upstream_per_minute: 30.0,
+            upstream_burst: 15,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    last_pruned: Instant,
+}
+
+// A token bucket per client address
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: f64, burst: u32) -> Self {
+        Self {
+            per_second: per_minute / 60.0,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+
+    // Takes a token from the client's bucket, or says how many seconds until there is one.
+    // Requests we can't tell the client of (Unix socket peers nothing was forwarded for) aren't limited
+    pub fn check(&self, client: Option<IpAddr>) -> Result<(), Error> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: Option<IpAddr>, now: Instant) -> Result<(), Error> {
+        let client = match client {
+            Some(client) if self.per_second > 0.0 => client_key(client),
+            _ => return Ok(()),
+        };
+
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock shouldn't be poisoned");
+
+        if now.duration_since(buckets.last_pruned) >= PRUNE_INTERVAL {
+            let mut clients = std::mem::take(&mut buckets.clients);
+            clients.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+            buckets.clients = clients;
+            buckets.last_pruned = now;
+        }
+
+        let burst = self.burst;
+        let bucket = buckets.clients
+            .entry(client)
+            .or_insert(Bucket { tokens: burst, updated: now });
+
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = ((1.0 - bucket.tokens) / self.per_second).ceil() as u64;
+
+        Err(Error::RateLimited(wait.max(1)))
+    }
+}
+
+// IPv6 clients usually get a whole /64, so they could just hop to the next address
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !(u64::MAX as u128)).into()),
+        ip => ip,
+    }
+}
+
+// Passed along to everything that may call an upstream on behalf of a client, so only
+// requests that actually miss the caches spend from the client's upstream bucket
+#[derive(Clone)]
+pub struct UpstreamBudget {
+    limiter: Arc<RateLimiter>,
+    client: Option<IpAddr>,
+    // Counts the calls that were let through
+    calls: Arc<AtomicUsize>,
+}
+
+impl UpstreamBudget {
+    pub fn new(limiter: Arc<RateLimiter>, client: Option<IpAddr>, calls: Arc<AtomicUsize>) -> Self {
+        Self { limiter, client, calls }
+    }
+
+    pub fn spend(&self) -> Result<(), Error> {
+        self.limiter.check(self.client)?;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(input: &str) -> Option<IpAddr> {
+        Some(input.parse().unwrap())
+    }
+
+    fn retry_after(result: Result<(), Error>) -> u64 {
+        match result {
+            Err(Error::RateLimited(seconds)) => seconds,
+            other => panic!("expected to be rate limited, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        // A token every 10 seconds
+        let limiter = RateLimiter::new(6.0, 2);
+        let start = Instant::now();
+        let client = ip("192.0.2.1");
+
+        assert!(limiter.check_at(client, start).is_ok());
+        assert!(limiter.check_at(client, start).is_ok());
+        assert_eq!(retry_after(limiter.check_at(client, start)), 10);
+
+        // Part of a token has come back
+        assert_eq!(retry_after(limiter.check_at(client, start + Duration::from_secs(4))), 6);
+
+        assert!(limiter.check_at(client, start + Duration::from_secs(10)).is_ok());
+        assert!(limiter.check_at(client, start + Duration::from_secs(10)).is_err());
+
+        // Never more than the burst, however long the client was away
+        let later = start + Duration::from_secs(3600);
+        assert!(limiter.check_at(client, later).is_ok());
+        assert!(limiter.check_at(client, later).is_ok());
+        assert!(limiter.check_at(client, later).is_err());
+
+        // Other clients have their own bucket
+        assert!(limiter.check_at(ip("192.0.2.2"), start).is_ok());
+    }
+
+    #[test]
+    fn retry_after_is_at_least_a_second() {
+        let limiter = RateLimiter::new(600.0, 1);
+        let start = Instant::now();
+
+        assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+        assert_eq!(retry_after(limiter.check_at(ip("192.0.2.1"), start)), 1);
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let limiter = RateLimiter::new(60.0, 5);
+        let start = Instant::now();
+
+        assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+        for _ in 0..5 {
+            let _ = limiter.check_at(ip("192.0.2.2"), start + PRUNE_INTERVAL - Duration::from_secs(2));
+        }
+
+        assert_eq!(limiter.buckets.lock().unwrap().clients.len(), 2);
+
+        // 192.0.2.1 is full again by then, 192.0.2.2 only has 2 of 5 tokens back
+        let _ = limiter.check_at(ip("192.0.2.3"), start + PRUNE_INTERVAL);
+
+        let mut clients = limiter.buckets.lock().unwrap().clients.keys().copied().collect::<Vec<_>>();
+        clients.sort();
+        assert_eq!(clients, vec![ip("192.0.2.2").unwrap(), ip("192.0.2.3").unwrap()]);
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        let limiter = RateLimiter::new(60.0, 1);
+        let start = Instant::now();
+
+        assert!(limiter.check_at(ip("2001:db8:1:2::1"), start).is_ok());
+        assert!(limiter.check_at(ip("2001:db8:1:2:ffff::9"), start).is_err());
+        assert!(limiter.check_at(ip("2001:db8:1:3::1"), start).is_ok());
+
+        // IPv4 mapped addresses are still limited per address
+        assert!(limiter.check_at(ip("::ffff:192.0.2.1"), start).is_ok());
+        assert!(limiter.check_at(ip("192.0.2.1"), start).is_err());
+        assert!(limiter.check_at(ip("::ffff:192.0.2.2"), start).is_ok());
+    }
+
+    #[test]
+    fn zero_turns_it_off() {
+        let limiter = RateLimiter::new(0.0, 1);
+        let start = Instant::now();
+
+        for _ in 0..1000 {
+            assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+        }
+
+        assert!(limiter.buckets.lock().unwrap().clients.is_empty());
+    }
+
+    #[test]
+    fn unknown_clients_are_not_limited() {
+        let limiter = RateLimiter::new(60.0, 1);
+
+        for _ in 0..10 {
+            assert!(limiter.check(None).is_ok());
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+use crate::{error::Error, http::{HttpRequest, HttpResponse, HttpVerb}};
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<HttpResponse, Error>> + Send>>;
+type BoxedHandler<S> = Box<dyn Fn(Arc<S>, Arc<HttpRequest>, Params) -> HandlerFuture + Send + Sync>;
+
+#[derive(Debug, Default)]
+pub struct Params(HashMap<String, String>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|x| x.as_str())
+    }
+}
+
+#[cfg(test)]
+impl<const N: usize> From<[(&str, &str); N]> for Params {
+    fn from(params: [(&str, &str); N]) -> Self {
+        Self(params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+}
+
+enum Segment {
+    Literal(String),
+    // ":name", or ":name.:ext" to split a known file extension off into a second param
+    Param {
+        name: String,
+        extension: Option<String>,
+    },
+}
+
+struct Route<S> {
+    verb: HttpVerb,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: BoxedHandler<S>,
+}
+
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+    extensions: Vec<String>,
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.trim_matches('/').split('/').collect()
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    // Extensions are only split off path params when they're in this list,
+    // anything else is considered part of the param itself
+    pub fn new(extensions: &[&str]) -> Self {
+        Self {
+            routes: Vec::new(),
+            extensions: extensions.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    pub fn route<F, Fut>(mut self, verb: HttpVerb, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Arc<S>, Arc<HttpRequest>, Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<HttpResponse, Error>> + Send + 'static,
+    {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|segment| match segment.strip_prefix(':') {
+                None => Segment::Literal(segment.to_owned()),
+                Some(param) => match param.split_once(".:") {
+                    Some((name, extension)) => Segment::Param {
+                        name: name.to_owned(),
+                        extension: Some(extension.to_owned()),
+                    },
+                    None => Segment::Param {
+                        name: param.to_owned(),
+                        extension: None,
+                    },
+                },
+            })
+            .collect();
+
+        self.routes.push(Route {
+            verb,
+            pattern: pattern.to_owned(),
+            segments,
+            handler: Box::new(move |state, request, params| Box::pin(handler(state, request, params))),
+        });
+
+        self
+    }
+
+    pub fn get<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Arc<S>, Arc<HttpRequest>, Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<HttpResponse, Error>> + Send + 'static,
+    {
+        self.route(HttpVerb::Get, pattern, handler)
+    }
+
+    fn match_segments(&self, segments: &[Segment], parts: &[&str]) -> Option<Params> {
+        if segments.len() != parts.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+
+        for (segment, part) in segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) => {
+                    if literal != part {
+                        return None;
+                    }
+                },
+                Segment::Param { name, extension } => {
+                    let split = extension.as_ref().and_then(|_| part.rsplit_once('.'))
+                        .filter(|(value, ext)| !value.is_empty() && self.extensions.iter().any(|x| x == ext));
+
+                    let value = match (split, extension) {
+                        (Some((value, ext)), Some(extension)) => {
+                            params.insert(extension.to_owned(), ext.to_owned());
+                            value
+                        },
+                        _ => part,
+                    };
+
+                    if value.is_empty() {
+                        return None;
+                    }
+
+                    params.insert(name.to_owned(), value.to_owned());
+                },
+            }
+        }
+
+        Some(Params(params))
+    }
+
+    // The pattern a path would be routed by, for labelling metrics without a label per emote
+    pub fn pattern_for(&self, path: &str) -> Option<&str> {
+        let parts = split_path(path);
+
+        self.routes.iter()
+            .find(|route| self.match_segments(&route.segments, &parts).is_some())
+            .map(|route| route.pattern.as_str())
+    }
+
+    pub async fn dispatch(&self, state: Arc<S>, request: Arc<HttpRequest>) -> Result<HttpResponse, Error> {
+        let parts = split_path(&request.pathname);
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            let params = match self.match_segments(&route.segments, &parts) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            // HEAD is served by GET handlers, the body is dropped when writing
+            let verb_matches = route.verb == request.verb
+                || (route.verb == HttpVerb::Get && request.verb == HttpVerb::Head);
+
+            if verb_matches {
+                return (route.handler)(state, request, params).await;
+            }
+
+            let name = route.verb.as_str();
+
+            if ! allowed.contains(&name) {
+                allowed.push(name);
+            }
+        }
+
+        if allowed.is_empty() {
+            return Err(Error::NotFound("Unknown path".to_owned()));
+        }
+
+        if allowed.contains(&"GET") {
+            allowed.push("HEAD");
+        }
+
+        allowed.push("OPTIONS");
+
+        Err(Error::MethodNotAllowed(allowed.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{HttpStatus, RequestLimits, RequestReader};
+
+    // Answers with the params it was called with, sorted so they can be compared
+    async fn echo(_: Arc<()>, _: Arc<HttpRequest>, params: Params) -> Result<HttpResponse, Error> {
+        let mut params: Vec<String> = params.0.iter().map(|(name, value)| format!("{name}={value}")).collect();
+        params.sort();
+
+        Ok(HttpResponse::new(HttpStatus::Ok).with_body("text/plain", params.join(" ").into_bytes()))
+    }
+
+    fn router() -> Router<()> {
+        Router::new(&["gif", "webp"])
+            .get("/:emote.:ext", echo)
+            .get("/:channel/:emote.:ext", echo)
+            .route(HttpVerb::Delete, "/cache/:channel/:emote", echo)
+    }
+
+    async fn dispatch(verb: &str, path: &str) -> Result<String, Error> {
+        let raw = format!("{verb} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let request = RequestReader::new(raw.as_bytes(), RequestLimits::default())
+            .next_request()
+            .await
+            .unwrap()
+            .unwrap();
+
+        let response = router().dispatch(Arc::new(()), Arc::new(request)).await?;
+        Ok(String::from_utf8(response.body).unwrap())
+    }
+
+    async fn allowed(verb: &str, path: &str) -> Option<String> {
+        match dispatch(verb, path).await {
+            Err(Error::MethodNotAllowed(allow)) => Some(allow),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn extensions_are_split_off() {
+        assert_eq!(dispatch("GET", "/KEKW").await.unwrap(), "emote=KEKW");
+        assert_eq!(dispatch("GET", "/KEKW.gif").await.unwrap(), "emote=KEKW ext=gif");
+        assert_eq!(dispatch("GET", "/a.b.gif").await.unwrap(), "emote=a.b ext=gif");
+        assert_eq!(dispatch("GET", "/user/KEKW.webp").await.unwrap(), "channel=user emote=KEKW ext=webp");
+    }
+
+    #[tokio::test]
+    async fn unknown_extensions_stay_part_of_the_param() {
+        assert_eq!(dispatch("GET", "/KEKW.bmp").await.unwrap(), "emote=KEKW.bmp");
+        assert_eq!(dispatch("GET", "/user/a.b").await.unwrap(), "channel=user emote=a.b");
+        // Nothing left to name the emote after splitting
+        assert_eq!(dispatch("GET", "/.gif").await.unwrap(), "emote=.gif");
+    }
+
+    #[tokio::test]
+    async fn empty_params_dont_match() {
+        assert!(matches!(dispatch("GET", "/").await, Err(Error::NotFound(_))));
+        assert!(matches!(dispatch("DELETE", "/cache//KEKW").await, Err(Error::NotFound(_))));
+        // Leading and trailing slashes are ignored
+        assert_eq!(dispatch("GET", "//KEKW.gif/").await.unwrap(), "emote=KEKW ext=gif");
+        assert!(matches!(dispatch("GET", "/a/b/c/d").await, Err(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn other_verbs_get_the_allowed_ones() {
+        assert_eq!(dispatch("HEAD", "/KEKW.gif").await.unwrap(), "emote=KEKW ext=gif");
+        assert_eq!(allowed("POST", "/KEKW.gif").await.as_deref(), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(allowed("PUT", "/user/KEKW.webp").await.as_deref(), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(allowed("GET", "/cache/user/KEKW").await.as_deref(), Some("DELETE, OPTIONS"));
+        assert_eq!(dispatch("DELETE", "/cache/user/KEKW").await.unwrap(), "channel=user emote=KEKW");
+    }
+
+    #[test]
+    fn patterns_for_metrics() {
+        let router = router();
+
+        assert_eq!(router.pattern_for("/KEKW.gif"), Some("/:emote.:ext"));
+        assert_eq!(router.pattern_for("/user/KEKW"), Some("/:channel/:emote.:ext"));
+        assert_eq!(router.pattern_for("/a/b/c/d"), None);
+    }
+}
@@ -0,0 +1,551 @@
+use std::{future::Future, path::{Path, PathBuf}, sync::Arc};
+
+use tokio::{fs, time};
+
+use crate::{
+    AppState,
+    compose::{self, Composition},
+    config::{EmoteSize, OutputFormat},
+    emote_puller::{Resize, Variant},
+    error::Error,
+    health,
+    http::{HttpRequest, HttpResponse, HttpStatus},
+    metrics,
+    range::{self, parse_range, RangeRequest},
+    rate_limit::UpstreamBudget,
+    router::{Params, Router},
+    seventv::SevenUserEmote,
+};
+
+// Pulled files never change for a given emote ID, but a keyword can point to
+// a different emote once the channel's set or the search ranking changes
+const ID_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+// Keywords joined with '+', like pepeD+RainTime
+const MAX_STACKED_EMOTES: usize = 8;
+// Keywords joined with ',', like KEKW,LULW,catJAM
+const MAX_STRIP_EMOTES: usize = 16;
+// Largest ?w= and ?h=, resizing is done frame by frame
+const MAX_RESIZE: u32 = 1024;
+// ?w= and ?h= are rounded to this, every size asked for is another cached file
+const RESIZE_STEP: u32 = 8;
+
+fn keyword_cache_control(state: &AppState) -> String {
+    format!("public, max-age={}", state.config.keyword_max_age)
+}
+
+// Routes are tried in order, so fixed prefixes have to come before catch-all params.
+// "id" is too short to be a twitch username so /id/... can't clash with /{channel}/{emote}
+pub fn router() -> Router<AppState> {
+    Router::new(&["gif", "webp", "png", "avif", "apng"])
+        .get("/metrics", serve_metrics)
+        .get("/healthz", serve_healthz)
+        .get("/readyz", serve_readyz)
+        .get("/id/:id.:ext", serve_emote_by_id)
+        .get("/:emote.:ext", serve_popular_emote)
+        .get("/:channel/:emote.:ext", serve_channel_emote)
+        .get("/:channel/strip/:emotes.:ext", serve_channel_strip)
+}
+
+// What the client may still spend on upstream calls, cache hits cost nothing
+fn upstream_budget(state: &AppState, http_request: &HttpRequest) -> UpstreamBudget {
+    UpstreamBudget::new(state.upstream_limiter.clone(), http_request.client_ip, http_request.upstream_calls.clone())
+}
+
+// Bounds the whole resolution, the actors still finish and cache the work of a request that timed out
+async fn resolve<T>(state: &AppState, resolution: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    time::timeout(state.config.upstream_timeout(), resolution)
+        .await
+        .unwrap_or(Err(Error::UpstreamTimeout(None)))
+}
+
+// "pepeD+RainTime" is pepeD with RainTime drawn on top of it
+fn emote_keywords(param: &str) -> Result<Vec<&str>, Error> {
+    let keywords: Vec<&str> = param.split('+').collect();
+
+    if keywords.iter().any(|x| x.is_empty()) {
+        return Err(Error::InvalidRequest("Empty emote keyword".to_owned()));
+    }
+
+    if keywords.len() > MAX_STACKED_EMOTES {
+        return Err(Error::InvalidRequest(format!("Can't stack more than {MAX_STACKED_EMOTES} emotes")));
+    }
+
+    Ok(keywords)
+}
+
+async fn pull_all(
+    state: &AppState,
+    emotes: Vec<SevenUserEmote>,
+    variant: Variant,
+    budget: &UpstreamBudget,
+) -> Result<Vec<(SevenUserEmote, PathBuf)>, Error> {
+    let mut pulled = Vec::with_capacity(emotes.len());
+
+    for emote in emotes {
+        let path = state.emote_puller.pull_emote(emote.clone(), variant, budget).await?;
+        pulled.push((emote, path));
+    }
+
+    Ok(pulled)
+}
+
+// Only zero-width emotes can go on top of another one, like in chat
+async fn pull_stacked(
+    state: &AppState,
+    mut emotes: Vec<SevenUserEmote>,
+    variant: Variant,
+    budget: &UpstreamBudget,
+) -> Result<(SevenUserEmote, PathBuf), Error> {
+    if let Some(emote) = emotes.iter().skip(1).find(|x| ! x.is_zero_width()) {
+        return Err(Error::InvalidRequest(format!("{} isn't a zero-width emote", emote.name)));
+    }
+
+    if emotes.len() == 1 {
+        let emote = emotes.remove(0);
+        let path = state.emote_puller.pull_emote(emote.clone(), variant, budget).await?;
+
+        return Ok((emote, path));
+    }
+
+    // Resizing and converting happen once the layers are put together
+    let layers = pull_all(state, emotes, variant.source(), budget).await?;
+    let base = layers[0].0.clone();
+    let path = state.emote_puller.compose_emotes(layers, Composition::Stack, variant).await?;
+
+    Ok((base, path))
+}
+
+// The configured spacing and background, unless the request asks for its own
+fn strip_composition(state: &AppState, http_request: &HttpRequest) -> Result<Composition, Error> {
+    let spacing = match http_request.query_param("spacing") {
+        Some(spacing) => spacing.parse::<u32>()
+            .ok()
+            .filter(|&x| x <= compose::MAX_SPACING)
+            .ok_or_else(|| Error::InvalidRequest(format!("spacing must be a number up to {}", compose::MAX_SPACING)))?,
+        None => state.config.strip.spacing,
+    };
+
+    let background = http_request.query_param("background")
+        .unwrap_or(&state.config.strip.background);
+    let background = compose::parse_background(background).map_err(Error::InvalidRequest)?;
+
+    Ok(Composition::Strip { spacing, background })
+}
+
+fn dimension(http_request: &HttpRequest, name: &str) -> Result<Option<u32>, Error> {
+    match http_request.query_param(name) {
+        Some(value) => value.parse::<u32>()
+            .ok()
+            .filter(|x| (1..=MAX_RESIZE).contains(x))
+            .map(|x| Some(((x + RESIZE_STEP / 2) / RESIZE_STEP).max(1) * RESIZE_STEP))
+            .ok_or_else(|| Error::InvalidRequest(format!("{name} must be a number from 1 to {MAX_RESIZE}"))),
+        None => Ok(None),
+    }
+}
+
+// What a request asks for. Which formats make sense depends on whether the emote
+// is animated, so the format is only settled once the emote is resolved
+#[derive(Debug, Clone, Copy)]
+struct WantedVariant {
+    size: EmoteSize,
+    resize: Option<Resize>,
+    // From the extension, otherwise negotiated through Accept
+    format: Option<OutputFormat>,
+}
+
+impl WantedVariant {
+    fn for_emote(&self, state: &AppState, http_request: &HttpRequest, animated: bool) -> Variant {
+        let format = match self.format {
+            Some(format) => format.for_emote(animated),
+            None => negotiate_format(state.config.default_format, http_request, animated),
+        };
+
+        Variant { size: self.size, resize: self.resize, format }
+    }
+
+    // Responses that went by Accept need a Vary: Accept
+    fn negotiated(&self) -> bool {
+        self.format.is_none()
+    }
+}
+
+// The format the client's Accept rates highest, ties go to the more specific match and then
+// to the order below. Nothing acceptable gets the default rather than a 406
+fn negotiate_format(default_format: OutputFormat, http_request: &HttpRequest, animated: bool) -> OutputFormat {
+    let default = match animated {
+        true => default_format.for_emote(true),
+        false => OutputFormat::Webp,
+    };
+
+    // Avif encodes slowly, gif loses alpha and colors
+    let preference = [default, OutputFormat::Webp, OutputFormat::Avif, OutputFormat::Png, OutputFormat::Gif];
+    let mut best = (default, 0.0, 0);
+
+    for format in preference.map(|x| x.for_emote(animated)) {
+        let (q, specificity) = http_request.accepts(format.mime());
+
+        if q > 0.0 && (q > best.1 || (q == best.1 && specificity > best.2)) {
+            best = (format, q, specificity);
+        }
+    }
+
+    best.0
+}
+
+// "KEKW@2x" asks for the 2x file, as does ?size=2x. The suffix wins when both are there.
+// Resized emotes start from the smallest size that's big enough unless one is asked for
+fn wanted_variant<'a>(
+    default_size: EmoteSize,
+    http_request: &HttpRequest,
+    params: &'a Params,
+    name: &str,
+) -> Result<(&'a str, WantedVariant), Error> {
+    let param = param(params, name)?;
+
+    let resize = match (dimension(http_request, "w")?, dimension(http_request, "h")?) {
+        (None, None) => None,
+        (width, height) => Some(Resize { width, height }),
+    };
+
+    let suffix = param.rsplit_once('@')
+        .and_then(|(rest, size)| Some((rest, size.parse::<EmoteSize>().ok()?)))
+        .filter(|(rest, _)| ! rest.is_empty());
+
+    let (param, size) = match suffix {
+        Some((param, size)) => (param, Some(size)),
+        None => match http_request.query_param("size") {
+            Some(size) => (param, Some(size.parse().map_err(Error::InvalidRequest)?)),
+            None => (param, None),
+        },
+    };
+
+    let size = match (size, resize) {
+        (Some(size), _) => size,
+        (None, Some(resize)) => EmoteSize::covering(resize.width.max(resize.height).unwrap_or(0)),
+        (None, None) => default_size,
+    };
+
+    // The router only splits off extensions it knows, so these always parse
+    let format = params.get("ext").and_then(|x| x.parse().ok());
+
+    Ok((param, WantedVariant { size, resize, format }))
+}
+
+fn param<'a>(params: &'a Params, name: &str) -> Result<&'a str, Error> {
+    params.get(name)
+        .ok_or(Error::InvalidRequest(format!("Missing {name}")))
+}
+
+async fn serve_metrics(
+    state: Arc<AppState>,
+    _http_request: Arc<HttpRequest>,
+    _params: Params,
+) -> Result<HttpResponse, Error> {
+    if ! state.config.metrics {
+        return Err(Error::NotFound("Unknown path".to_owned()));
+    }
+
+    let body = metrics::render(&[
+        ("emote_manager", state.emote_manager.mailbox_depth()),
+        ("emote_puller", state.emote_puller.mailbox_depth()),
+    ]);
+
+    let mut response = HttpResponse::ok("text/plain; version=0.0.4; charset=utf-8", body.into_bytes());
+    response.set_header("Cache-Control", "no-store");
+
+    Ok(response)
+}
+
+async fn serve_healthz(
+    state: Arc<AppState>,
+    _http_request: Arc<HttpRequest>,
+    _params: Params,
+) -> Result<HttpResponse, Error> {
+    Ok(health::healthz(&state).await)
+}
+
+async fn serve_readyz(
+    state: Arc<AppState>,
+    _http_request: Arc<HttpRequest>,
+    _params: Params,
+) -> Result<HttpResponse, Error> {
+    Ok(health::readyz(&state).await)
+}
+
+async fn serve_emote_by_id(
+    state: Arc<AppState>,
+    http_request: Arc<HttpRequest>,
+    params: Params,
+) -> Result<HttpResponse, Error> {
+    let (emote_id, wanted) = wanted_variant(state.config.emote_size, &http_request, &params, "id")?;
+
+    if ! emote_id.chars().all(|x| x.is_ascii_alphanumeric()) {
+        return Err(Error::NotFound("Emote not found".to_owned()));
+    }
+
+    let budget = upstream_budget(&state, &http_request);
+    let (emote, path) = resolve(&state, async {
+        let emote = state.emote_manager.get_emote_by_id(emote_id, &budget).await?;
+        let variant = wanted.for_emote(&state, &http_request, emote.animated);
+        let path = state.emote_puller.pull_emote(emote.clone(), variant, &budget).await?;
+
+        Ok((emote, path))
+    }).await?;
+
+    serve_emote_file(&http_request, &emote, &path, ID_CACHE_CONTROL, wanted.negotiated(), true).await
+}
+
+async fn serve_popular_emote(
+    state: Arc<AppState>,
+    http_request: Arc<HttpRequest>,
+    params: Params,
+) -> Result<HttpResponse, Error> {
+    let (emote, wanted) = wanted_variant(state.config.emote_size, &http_request, &params, "emote")?;
+    let emote_keywords = emote_keywords(emote)?;
+
+    let budget = upstream_budget(&state, &http_request);
+    let (emote, path) = resolve(&state, async {
+        let mut emotes = Vec::with_capacity(emote_keywords.len());
+
+        for keyword in emote_keywords {
+            emotes.push(state.emote_manager.get_popular_emote(keyword, &budget).await?);
+        }
+
+        let variant = wanted.for_emote(&state, &http_request, emotes.iter().any(|x| x.animated));
+
+        pull_stacked(&state, emotes, variant, &budget).await
+    }).await?;
+
+    serve_emote_file(&http_request, &emote, &path, &keyword_cache_control(&state), wanted.negotiated(), false).await
+}
+
+async fn serve_channel_emote(
+    state: Arc<AppState>,
+    http_request: Arc<HttpRequest>,
+    params: Params,
+) -> Result<HttpResponse, Error> {
+    let username = param(&params, "channel")?;
+    let (emote, wanted) = wanted_variant(state.config.emote_size, &http_request, &params, "emote")?;
+    let emote_keywords = emote_keywords(emote)?;
+
+    let budget = upstream_budget(&state, &http_request);
+    let (emote, path) = resolve(&state, async {
+        let twitch_id = state.twitch_client.get_id_for_username(username, &budget).await?;
+        let mut emotes = Vec::with_capacity(emote_keywords.len());
+
+        for keyword in emote_keywords {
+            emotes.push(state.emote_manager.get_user_emote(&twitch_id, keyword, &budget).await?);
+        }
+
+        let variant = wanted.for_emote(&state, &http_request, emotes.iter().any(|x| x.animated));
+
+        pull_stacked(&state, emotes, variant, &budget).await
+    }).await?;
+
+    serve_emote_file(&http_request, &emote, &path, &keyword_cache_control(&state), wanted.negotiated(), false).await
+}
+
+async fn serve_channel_strip(
+    state: Arc<AppState>,
+    http_request: Arc<HttpRequest>,
+    params: Params,
+) -> Result<HttpResponse, Error> {
+    let username = param(&params, "channel")?;
+    let (emotes, wanted) = wanted_variant(state.config.emote_size, &http_request, &params, "emotes")?;
+    let emote_keywords: Vec<&str> = emotes.split(',').collect();
+
+    if emote_keywords.iter().any(|x| x.is_empty()) {
+        return Err(Error::InvalidRequest("Empty emote keyword".to_owned()));
+    }
+
+    if emote_keywords.len() > MAX_STRIP_EMOTES {
+        return Err(Error::InvalidRequest(format!("Strips can't have more than {MAX_STRIP_EMOTES} emotes")));
+    }
+
+    let composition = strip_composition(&state, &http_request)?;
+
+    let budget = upstream_budget(&state, &http_request);
+    let (emote, path) = resolve(&state, async {
+        let twitch_id = state.twitch_client.get_id_for_username(username, &budget).await?;
+        let mut emotes = Vec::with_capacity(emote_keywords.len());
+
+        for keyword in emote_keywords {
+            // Recaps quote global emotes too, those aren't in the channel's set
+            let emote = match state.emote_manager.get_user_emote(&twitch_id, keyword, &budget).await {
+                Err(Error::NotFound(_)) => state.emote_manager.get_popular_emote(keyword, &budget).await?,
+                emote => emote?,
+            };
+
+            emotes.push(emote);
+        }
+
+        // Resizing and converting happen once the strip is put together
+        let animated = emotes.iter().any(|x| x.animated);
+        let variant = wanted.for_emote(&state, &http_request, animated);
+        let emotes = pull_all(&state, emotes, variant.source(), &budget).await?;
+        let first = emotes[0].0.clone();
+        let path = state.emote_puller.compose_emotes(emotes, composition, variant).await?;
+
+        Ok((first, path))
+    }).await?;
+
+    serve_emote_file(&http_request, &emote, &path, &keyword_cache_control(&state), wanted.negotiated(), false).await
+}
+
+// Keyword routes can be remapped to an older file, so only the ETag (which
+// names the emote id) validates them, never the file's mtime
+async fn serve_emote_file(
+    http_request: &HttpRequest,
+    emote: &SevenUserEmote,
+    path: &Path,
+    cache_control: &str,
+    negotiated: bool,
+    dated: bool,
+) -> Result<HttpResponse, Error> {
+    let filename = path.file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_else(|| emote.id.clone());
+
+    let mime = path.extension()
+        .and_then(|x| x.to_str()?.parse::<OutputFormat>().ok())
+        .unwrap_or(OutputFormat::Webp)
+        .mime();
+
+    let last_modified = match dated {
+        true => fs::metadata(path)
+            .await
+            .map_err(|x| Error::io(format!("Failed to stat emote file {}", emote.id), x))?
+            .modified()
+            .ok(),
+        false => None,
+    };
+    let etag = format!("\"{filename}\"");
+
+    let mut response = if http_request.is_not_modified(&etag, last_modified) {
+        HttpResponse::new(HttpStatus::NotModified)
+    } else {
+        let emote_file = fs::read(path)
+            .await
+            .map_err(|x| Error::io(format!("Failed to read emote file {}", emote.id), x))?;
+
+        let range = http_request.header("Range")
+            .filter(|_| http_request.if_range_matches(&etag, last_modified))
+            .map(|x| parse_range(x, emote_file.len()))
+            .unwrap_or(RangeRequest::Full);
+
+        match range {
+            RangeRequest::Full => HttpResponse::ok(mime, emote_file),
+            RangeRequest::Partial(ranges) => range::partial_response(&ranges, mime, &emote_file),
+            RangeRequest::Unsatisfiable => range::unsatisfiable_response(emote_file.len()),
+        }
+    };
+
+    response.set_header("Accept-Ranges", "bytes");
+    response.set_header("ETag", &etag);
+    response.set_header("Cache-Control", cache_control);
+
+    if negotiated {
+        response.add_vary("Accept");
+    }
+
+    if let Some(last_modified) = last_modified {
+        response.set_header("Last-Modified", &httpdate::fmt_http_date(last_modified));
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{RequestLimits, RequestReader};
+
+    async fn request(target: &str, headers: &str) -> HttpRequest {
+        let raw = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+
+        RequestReader::new(raw.as_bytes(), RequestLimits::default())
+            .next_request()
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn negotiate(accept: &str, animated: bool) -> OutputFormat {
+        let headers = match accept {
+            "" => String::new(),
+            accept => format!("Accept: {accept}\r\n"),
+        };
+
+        negotiate_format(OutputFormat::Gif, &request("/", &headers).await, animated)
+    }
+
+    #[tokio::test]
+    async fn formats_follow_accept() {
+        // Highest q wins
+        assert_eq!(negotiate("image/gif;q=0.5, image/avif;q=0.9", true).await, OutputFormat::Avif);
+        assert_eq!(negotiate("image/png, image/gif;q=0.4", false).await, OutputFormat::Png);
+        assert_eq!(negotiate("image/png, image/gif;q=0.4", true).await, OutputFormat::Gif);
+        assert_eq!(negotiate("image/apng, image/gif;q=0.4", true).await, OutputFormat::Apng);
+
+        // On a tie the exact type beats image/*, and then the order of preference decides
+        assert_eq!(negotiate("image/*, image/avif", true).await, OutputFormat::Avif);
+        assert_eq!(negotiate("image/*", true).await, OutputFormat::Gif);
+        assert_eq!(negotiate("image/webp, image/avif", true).await, OutputFormat::Webp);
+
+        // q=0 rules a format out even when a wider range allows it
+        assert_eq!(negotiate("image/*, image/gif;q=0", true).await, OutputFormat::Webp);
+        assert_eq!(negotiate("image/*;q=0.1, image/webp;q=0", false).await, OutputFormat::Avif);
+    }
+
+    #[tokio::test]
+    async fn unacceptable_formats_get_the_default() {
+        assert_eq!(negotiate("", true).await, OutputFormat::Gif);
+        assert_eq!(negotiate("", false).await, OutputFormat::Webp);
+        assert_eq!(negotiate("text/html", true).await, OutputFormat::Gif);
+        assert_eq!(negotiate("image/*;q=0", false).await, OutputFormat::Webp);
+
+        let request = request("/", "Accept: text/html\r\n").await;
+        assert_eq!(negotiate_format(OutputFormat::Png, &request, true), OutputFormat::Apng);
+    }
+
+    async fn wanted(param: &str, target: &str) -> Result<(String, EmoteSize, Option<Resize>), Error> {
+        let params = Params::from([("emote", param), ("ext", "gif")]);
+        let request = request(target, "").await;
+
+        wanted_variant(EmoteSize::X3, &request, &params, "emote")
+            .map(|(param, wanted)| (param.to_owned(), wanted.size, wanted.resize))
+    }
+
+    #[tokio::test]
+    async fn sizes_from_suffix_or_query() {
+        assert_eq!(wanted("KEKW", "/").await.unwrap(), ("KEKW".to_owned(), EmoteSize::X3, None));
+        assert_eq!(wanted("KEKW@2x", "/").await.unwrap(), ("KEKW".to_owned(), EmoteSize::X2, None));
+        assert_eq!(wanted("KEKW", "/?size=1x").await.unwrap(), ("KEKW".to_owned(), EmoteSize::X1, None));
+        assert_eq!(wanted("KEKW@2x", "/?size=1x").await.unwrap(), ("KEKW".to_owned(), EmoteSize::X2, None));
+
+        // Only the last '@' can start a size, and only when something comes before it
+        assert_eq!(wanted("a@b@4x", "/").await.unwrap(), ("a@b".to_owned(), EmoteSize::X4, None));
+        assert_eq!(wanted("KEKW@9x", "/").await.unwrap(), ("KEKW@9x".to_owned(), EmoteSize::X3, None));
+        assert_eq!(wanted("@2x", "/").await.unwrap(), ("@2x".to_owned(), EmoteSize::X3, None));
+
+        assert!(matches!(wanted("KEKW", "/?size=9x").await, Err(Error::InvalidRequest(_))));
+        assert!(matches!(wanted("KEKW", "/?size=2").await, Err(Error::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn resizes_pick_a_covering_size() {
+        let resize = |width, height| Some(Resize { width, height });
+
+        assert_eq!(wanted("KEKW", "/?w=50").await.unwrap().1, EmoteSize::X2);
+        assert_eq!(wanted("KEKW", "/?w=50").await.unwrap().2, resize(Some(48), None));
+        assert_eq!(wanted("KEKW", "/?h=3&w=20").await.unwrap().2, resize(Some(24), Some(8)));
+        assert_eq!(wanted("KEKW", "/?w=20&h=1000").await.unwrap().1, EmoteSize::X4);
+
+        // An explicit size still wins
+        assert_eq!(wanted("KEKW@1x", "/?w=200").await.unwrap().1, EmoteSize::X1);
+
+        for target in ["/?w=0", "/?h=1025", "/?w=-5", "/?w=big"] {
+            assert!(matches!(wanted("KEKW", target).await, Err(Error::InvalidRequest(_))), "{target}");
+        }
+    }
+}
@@ -1,9 +1,12 @@
-use std::{path::PathBuf, env, io::Cursor};
+use std::{future::Future, path::Path, io::Cursor, sync::Mutex, time::Duration};
 
 use serde::{Deserialize, Serialize};
-use reqwest::{self, Client};
+use serde_json::json;
+use reqwest::{Client, StatusCode};
 use tokio::{fs, io};
 
+use crate::{config::EmoteSize, error::{Error, Upstream}, metrics, utils::now_secs};
+
 #[derive(Debug, Serialize, Deserialize)]
 struct DataResponse<T> {
     data: T,
@@ -22,11 +25,26 @@ pub struct SevenUserEmoteSet {
     pub emotes: Vec<SevenUserEmote>,
 }
 
+// 7tv's emote flags, see EmoteFlagsModel in their API
+const EMOTE_FLAG_ZERO_WIDTH: u32 = 1 << 8;
+// The flags of an emote set entry are a different set where zero-width is the first bit
+const ACTIVE_EMOTE_FLAG_ZERO_WIDTH: u32 = 1 << 0;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SevenUserEmote {
     pub id: String,
     pub name: String,
     pub animated: bool,
+    // Always emote flags, the ones of emote set entries are translated when the set is fetched
+    #[serde(default)]
+    pub flags: u32,
+}
+
+impl SevenUserEmote {
+    // Zero-width emotes are drawn over the emote before them instead of next to it
+    pub fn is_zero_width(&self) -> bool {
+        self.flags & EMOTE_FLAG_ZERO_WIDTH != 0
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,74 +57,174 @@ pub struct SevenEmotesDataWrapper {
     emotes: SevenEmotesData,
 }
 
-pub async fn get_twitch_user_emote_set(
-    twitch_id: &str,
-) -> Result<SevenUserEmoteSet, String> {
-    let resp = reqwest::get(format!("https://7tv.io/v3/users/twitch/{twitch_id}"))
-        .await
-        .map_err(|x| x.to_string())?;
-
-    let json = resp
-        .json::<SevenUserData>()
-        .await
-        .map_err(|x| x.to_string())?;
+// How the most recent call to 7tv went, for the readiness check
+#[derive(Debug, Clone)]
+pub struct CallStatus {
+    pub at: u64, // seconds
+    pub failure: Option<String>,
+}
 
-    Ok(json.emote_set)
+#[derive(Debug)]
+pub struct SevenTvClient {
+    client: Client,
+    api_url: String,
+    cdn_url: String,
+    last_call: Mutex<Option<CallStatus>>,
 }
 
-pub async fn get_most_popular_emote(
-    emote_keyword: &str,
-) -> Result<SevenUserEmote, String> {
-    let client = Client::new();
-    let resp = client.post("https://7tv.io/v3/gql")
-        .header("Content-Type", "application/json")
-        .body(format!(r#"{{
-            "query": "query SearchEmotes($query: String!) {{ emotes(query: $query filter: {{ case_sensitive: true, exact_match: true }} sort: {{ value: \"popularity\", order: DESCENDING }}) {{ items {{ id name animated }} }} }}",
-            "variables": {{
-                "query": "{emote_keyword}"
-            }}
-        }}"#))
-        .send()
-        .await
-        .map_err(|x| x.to_string())?;
-
-    let json = resp
-        .json::<DataResponse<SevenEmotesDataWrapper>>()
-        .await
-        .map_err(|x| x.to_string())?;
-
-    let emote = json.data.emotes.items.into_iter().nth(0);
-
-    match emote {
-        Some(emote) => Ok(emote),
-        None => Err("Emote not found".to_owned()),
+impl SevenTvClient {
+    // The timeout applies to every call, the actors wait on them one at a time
+    pub fn new(api_url: String, cdn_url: String, timeout: Duration) -> Self {
+        Self {
+            client: Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("Should be able to build the HTTP client"),
+            api_url: api_url.trim_end_matches('/').to_owned(),
+            cdn_url: cdn_url.trim_end_matches('/').to_owned(),
+            last_call: Mutex::new(None),
+        }
     }
-}
 
-pub async fn download_emote(emote_id: &str) -> Result<PathBuf, String> {
-    // TODO: size is hardcoded for now
-    let url = format!("https://cdn.7tv.app/emote/{emote_id}/4x.webp");
-    let response = reqwest::get(url)
-        .await
-        .map_err(|x| x.to_string())?;
-    let bytes = response.bytes()
-        .await
-        .map_err(|x| x.to_string())?;
-
-    let path = {
-        let mut dir = env::temp_dir();
-        dir.push(format!("{emote_id}.webp"));
-        dir
-    };
-    let mut file = fs::File::create(path.as_path())
-        .await
-        .map_err(|x| x.to_string())?;
-    
-    let mut content = Cursor::new(bytes);
-
-    io::copy(&mut content, &mut file)
-        .await
-        .map_err(|x| x.to_string())?;
-    
-    Ok(path)
+    // Every call goes through here, for the metrics and the last call status
+    async fn call<T>(&self, name: &'static str, request: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        let result = metrics::upstream_call(Upstream::SevenTv, name, request).await;
+
+        let failure = match &result {
+            Err(err) if err.is_upstream_failure() => Some(err.to_string()),
+            _ => None,
+        };
+
+        *self.last_call.lock().expect("Last call lock shouldn't be poisoned") = Some(CallStatus { at: now_secs(), failure });
+
+        result
+    }
+
+    // None until the first call
+    pub fn last_call(&self) -> Option<CallStatus> {
+        self.last_call.lock().expect("Last call lock shouldn't be poisoned").clone()
+    }
+
+    pub async fn get_twitch_user_emote_set(
+        &self,
+        twitch_id: &str,
+    ) -> Result<SevenUserEmoteSet, Error> {
+        self.call("user_emote_set", async {
+            let resp = self.client.get(format!("{}/users/twitch/{twitch_id}", self.api_url))
+                .send()
+                .await
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?;
+
+            if resp.status() == StatusCode::NOT_FOUND {
+                return Err(Error::NotFound("7tv: User not found".to_owned()));
+            }
+
+            let json = resp
+                .error_for_status()
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?
+                .json::<SevenUserData>()
+                .await
+                .map_err(|x| Error::upstream_decode(Upstream::SevenTv, x))?;
+
+            let mut emote_set = json.emote_set;
+
+            for emote in emote_set.emotes.iter_mut() {
+                emote.flags = match emote.flags & ACTIVE_EMOTE_FLAG_ZERO_WIDTH {
+                    0 => 0,
+                    _ => EMOTE_FLAG_ZERO_WIDTH,
+                };
+            }
+
+            Ok(emote_set)
+        }).await
+    }
+
+    pub async fn get_most_popular_emote(
+        &self,
+        emote_keyword: &str,
+    ) -> Result<SevenUserEmote, Error> {
+        self.call("emote_search", async {
+            let resp = self.client.post(format!("{}/gql", self.api_url))
+                // Keywords come straight from the decoded request path, so let serde escape them
+                .json(&json!({
+                    "query": r#"query SearchEmotes($query: String!) { emotes(query: $query filter: { case_sensitive: true, exact_match: true } sort: { value: "popularity", order: DESCENDING }) { items { id name animated flags } } }"#,
+                    "variables": {
+                        "query": emote_keyword,
+                    },
+                }))
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?;
+
+            let json = resp
+                .json::<DataResponse<SevenEmotesDataWrapper>>()
+                .await
+                .map_err(|x| Error::upstream_decode(Upstream::SevenTv, x))?;
+
+            let emote = json.data.emotes.items.into_iter().next();
+
+            match emote {
+                Some(emote) => Ok(emote),
+                None => Err(Error::NotFound("Emote not found".to_owned())),
+            }
+        }).await
+    }
+
+    pub async fn get_emote_by_id(
+        &self,
+        emote_id: &str,
+    ) -> Result<SevenUserEmote, Error> {
+        self.call("emote", async {
+            let resp = self.client.get(format!("{}/emotes/{emote_id}", self.api_url))
+                .send()
+                .await
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?;
+
+            // 7tv answers malformed IDs with a 400
+            if matches!(resp.status(), StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST) {
+                return Err(Error::NotFound("Emote not found".to_owned()));
+            }
+
+            resp.error_for_status()
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?
+                .json::<SevenUserEmote>()
+                .await
+                .map_err(|x| Error::upstream_decode(Upstream::SevenTv, x))
+        }).await
+    }
+
+    // Downloads the webp source of an emote to `path`
+    pub async fn download_emote(&self, emote_id: &str, size: EmoteSize, path: &Path) -> Result<(), Error> {
+        self.call("download", async {
+            let url = format!("{}/emote/{emote_id}/{size}.webp", self.cdn_url);
+            let response = self.client.get(url)
+                .send()
+                .await
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?;
+
+            // Not every emote has every size
+            if response.status() == StatusCode::NOT_FOUND {
+                return Err(Error::NotFound(format!("7tv: No {size} file for emote {emote_id}")));
+            }
+
+            let response = response.error_for_status()
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?;
+            let bytes = response.bytes()
+                .await
+                .map_err(|x| Error::upstream_http(Upstream::SevenTv, x))?;
+
+            let mut file = fs::File::create(path)
+                .await
+                .map_err(|x| Error::io("Failed to create temporary emote file", x))?;
+
+            let mut content = Cursor::new(bytes);
+
+            io::copy(&mut content, &mut file)
+                .await
+                .map_err(|x| Error::io("Failed to write temporary emote file", x))?;
+
+            Ok(())
+        }).await
+    }
 }
@@ -0,0 +1,163 @@
+use std::{fs, io::BufReader, net::SocketAddr, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use serde::Deserialize;
+use tokio::{signal::unix::{signal, SignalKind}, time};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tracing::{error, info};
+
+use crate::{error::Error, http::{HttpRequest, HttpResponse, HttpStatus}, listener::{self, ListenAddr}};
+
+// How often the certificate files are checked for changes
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // TLS is only served when both of these are set
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    #[serde(deserialize_with = "listener::deserialize_listen")]
+    pub listen: Vec<ListenAddr>,
+    // Answer every plain HTTP request with a redirect to the TLS listener instead of serving it
+    pub redirect_http: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 8443)))],
+            redirect_http: false,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert_path.is_some() && self.key_path.is_some()
+    }
+}
+
+fn load_server_config(cert_path: &Path, key_path: &Path, http2: bool) -> Result<ServerConfig, Error> {
+    let invalid = |message: String| Error::Config(message);
+
+    let cert_file = fs::File::open(cert_path)
+        .map_err(|x| Error::io(format!("Failed to open certificate {}", cert_path.display()), x))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|x| Error::io(format!("Failed to read certificate {}", cert_path.display()), x))?;
+
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificates found in {}", cert_path.display())));
+    }
+
+    let key_file = fs::File::open(key_path)
+        .map_err(|x| Error::io(format!("Failed to open private key {}", key_path.display()), x))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .map_err(|x| Error::io(format!("Failed to read private key {}", key_path.display()), x))?
+        .ok_or_else(|| invalid(format!("No private key found in {}", key_path.display())))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|x| invalid(format!("Invalid certificate or key: {x}")))?;
+
+    // Listed in order of preference
+    config.alpn_protocols = match http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+
+    Ok(config)
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+// Holds the current acceptor, swapped out whenever the certificate is reloaded.
+// Connections that already finished their handshake keep the old certificate
+pub struct TlsReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    http2: bool,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl TlsReloader {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf, http2: bool) -> Result<Self, Error> {
+        let config = load_server_config(&cert_path, &key_path, http2)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            http2,
+            acceptor: RwLock::new(TlsAcceptor::from(Arc::new(config))),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().expect("TLS acceptor lock is poisoned").clone()
+    }
+
+    // A broken certificate on disk keeps the previous one in use
+    fn reload(&self) {
+        match load_server_config(&self.cert_path, &self.key_path, self.http2) {
+            Ok(config) => {
+                *self.acceptor.write().expect("TLS acceptor lock is poisoned") = TlsAcceptor::from(Arc::new(config));
+                info!("Reloaded TLS certificate {}", self.cert_path.display());
+            },
+            Err(err) => {
+                error!("Failed to reload TLS certificate, keeping the current one: {err}");
+            },
+        }
+    }
+
+    // Reloads on SIGHUP, and when either file's modification time changes
+    pub async fn watch(self: Arc<Self>) {
+        let mut hangup = signal(SignalKind::hangup())
+            .expect("Should be able to listen for SIGHUP");
+        let mut poll = time::interval(RELOAD_POLL_INTERVAL);
+        let mut last_modified = (modified_at(&self.cert_path), modified_at(&self.key_path));
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {},
+                _ = poll.tick() => {
+                    let modified = (modified_at(&self.cert_path), modified_at(&self.key_path));
+
+                    if modified == last_modified {
+                        continue;
+                    }
+                },
+            }
+
+            last_modified = (modified_at(&self.cert_path), modified_at(&self.key_path));
+            self.reload();
+        }
+    }
+}
+
+// Sends a plain HTTP request to the same host and path on the TLS listener
+pub fn redirect_response(request: &HttpRequest, https_port: u16) -> HttpResponse {
+    let host = match request.header("Host") {
+        Some(host) => host,
+        None => return HttpResponse::error(HttpStatus::BadRequest, "Missing Host header"),
+    };
+
+    // Drop the plain port, keeping bracketed IPv6 hosts intact
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if ! port.contains(']') => hostname,
+        _ => host,
+    };
+
+    let location = match https_port {
+        443 => format!("https://{hostname}{}", request.target),
+        port => format!("https://{hostname}:{port}{}", request.target),
+    };
+
+    let mut response = HttpResponse::new(HttpStatus::PermanentRedirect);
+    response.set_header("Location", &location);
+    response
+}
@@ -1,10 +1,18 @@
-use std::{time::{self, UNIX_EPOCH}, collections::HashMap, cell::RefCell};
+use std::{time::{self, Duration, UNIX_EPOCH}, collections::HashMap};
 use tokio::sync::RwLock;
 use reqwest::{self, Client};
 use serde::{Deserialize, Serialize};
+use tracing::info;
 
-use crate::utils::now_secs;
+use crate::{utils::now_secs, error::{Error, Upstream}, metrics::{self, METRICS}, rate_limit::UpstreamBudget};
 
+const MAX_LOGIN_LENGTH: usize = 25;
+
+// Twitch answers anything else with a 400, no such user can exist
+fn is_valid_login(username: &str) -> bool {
+    (1..=MAX_LOGIN_LENGTH).contains(&username.len())
+        && username.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'_')
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct TwitchAuthDataResponse {
@@ -34,23 +42,32 @@ type TwitchUserDataResponse = ResponseDataWrapper<Vec<TwitchUserData>>;
 
 #[derive(Debug)]
 pub struct TwitchClient {
+    client: Client,
     client_id: String,
     client_secret: String,
+    api_url: String,
+    auth_url: String,
     auth_token: RwLock<Option<(String, u64)>>,
     twitch_username_id_map: RwLock<HashMap<String, String>>,
 }
 
 impl TwitchClient {
-    pub fn new(client_id: String, client_secret: String) -> Self {
+    pub fn new(client_id: String, client_secret: String, api_url: String, auth_url: String, timeout: Duration) -> Self {
         Self {
+            client: Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("Should be able to build the HTTP client"),
             client_id,
             client_secret,
+            api_url: api_url.trim_end_matches('/').to_owned(),
+            auth_url: auth_url.trim_end_matches('/').to_owned(),
             auth_token: RwLock::new(None),
             twitch_username_id_map: RwLock::new(HashMap::new()),
         }
     }
 
-    async fn get_auth_token(&self) -> Result<String, String> {
+    pub async fn get_auth_token(&self) -> Result<String, Error> {
         let now = time::SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
@@ -70,65 +87,78 @@ impl TwitchClient {
         Ok(token)
     }
 
-    async fn update_auth_token(&self) -> Result<String, String> {
-        let client = Client::new();
-        let query = vec![
-            ("client_id", self.client_id.as_str()),
-            ("client_secret", self.client_secret.as_str()),
-            ("grant_type", "client_credentials"),
-        ];
-
-        let response = client.post("https://id.twitch.tv/oauth2/token")
-            .query(&query)
-            .send()
-            .await
-            .map_err(|x| x.to_string())?;
+    async fn update_auth_token(&self) -> Result<String, Error> {
+        metrics::upstream_call(Upstream::Twitch, "token", async {
+            let query = vec![
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("grant_type", "client_credentials"),
+            ];
+
+            let response = self.client.post(format!("{}/token", self.auth_url))
+                .query(&query)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .map_err(|x| Error::upstream_http(Upstream::Twitch, x))?;
         
-        let json = response
-            .json::<TwitchAuthDataResponse>()
-            .await
-            .map_err(|x| x.to_string())?;
+            let json = response
+                .json::<TwitchAuthDataResponse>()
+                .await
+                .map_err(|x| Error::upstream_decode(Upstream::Twitch, x))?;
 
-        let token = json.access_token;
-        let expires_in = json.expires_in;
+            let token = json.access_token;
+            let expires_in = json.expires_in;
 
-        let now = now_secs();
-        let expires_at = now + expires_in;
+            let now = now_secs();
+            let expires_at = now + expires_in;
 
-        let mut auth_token = self.auth_token.write().await;
-        *auth_token = Some((token.clone(), expires_at));
-        println!("Fetched new auth token");
+            let mut auth_token = self.auth_token.write().await;
+            *auth_token = Some((token.clone(), expires_at));
+            METRICS.twitch_token_refreshes.inc(&[]);
+            info!("Fetched new Twitch auth token");
 
-        Ok(token.clone())
+            Ok(token.clone())
+        }).await
     }
 
-    pub async fn get_id_for_username(&self, username: &str) -> Result<String, String> {
+    pub async fn get_id_for_username(&self, username: &str, budget: &UpstreamBudget) -> Result<String, Error> {
+        if ! is_valid_login(username) {
+            return Err(Error::NotFound("Twitch: User not found".to_owned()));
+        }
+
         let username_map = self.twitch_username_id_map.read().await;
 
         if let Some(id) = username_map.get(username) {
+            METRICS.emote_cache.inc(&[("cache", "twitch_users"), ("result", "hit")]);
             return Ok(id.clone());
         }
         drop(username_map);
 
-        let auth_token = self.get_auth_token().await?;
+        METRICS.emote_cache.inc(&[("cache", "twitch_users"), ("result", "miss")]);
 
-        let client = Client::new();
-        let response = client.get("https://api.twitch.tv/helix/users")
-            .header("Client-Id", &self.client_id)
-            .header("Authorization", format!("Bearer {auth_token}"))
-            .query(&[("login", &username)])
-            .send()
-            .await
-            .map_err(|x| x.to_string())?;
+        budget.spend()?;
+        let auth_token = self.get_auth_token().await?;
 
-        let json = response
-            .json::<TwitchUserDataResponse>()
-            .await
-            .map_err(|x| x.to_string())?;
+        let json = metrics::upstream_call(Upstream::Twitch, "users", async {
+            let response = self.client.get(format!("{}/users", self.api_url))
+                .header("Client-Id", &self.client_id)
+                .header("Authorization", format!("Bearer {auth_token}"))
+                .query(&[("login", &username)])
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .map_err(|x| Error::upstream_http(Upstream::Twitch, x))?;
+
+            response
+                .json::<TwitchUserDataResponse>()
+                .await
+                .map_err(|x| Error::upstream_decode(Upstream::Twitch, x))
+        }).await?;
 
         let data = json.data
-            .get(0)
-            .ok_or("Empty user data")?;
+            .first()
+            .ok_or(Error::NotFound("Twitch: User not found".to_owned()))?;
 
         let id = data.id.clone();
 
@@ -139,3 +169,21 @@ impl TwitchClient {
         Ok(id.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_names() {
+        assert!(is_valid_login("xqc"));
+        assert!(is_valid_login("Some_Name_123"));
+        assert!(is_valid_login(&"a".repeat(MAX_LOGIN_LENGTH)));
+
+        assert!(! is_valid_login(""));
+        assert!(! is_valid_login("some-name"));
+        assert!(! is_valid_login("some name"));
+        assert!(! is_valid_login("ünicode"));
+        assert!(! is_valid_login(&"a".repeat(MAX_LOGIN_LENGTH + 1)));
+    }
+}